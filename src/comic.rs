@@ -0,0 +1,364 @@
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::date::parse_date;
+use crate::scanner::BookFormat;
+use crate::{BookMetadata, Contributor, ParsedBook};
+
+pub const CBZ_MIME: &str = "application/vnd.comicbook+zip";
+pub const CBR_MIME: &str = "application/vnd.comicbook-rar";
+pub const CB7_MIME: &str = "application/x-cb7";
+
+const COMIC_INFO: &str = "comicinfo.xml";
+
+//ComicInfo.xml credits and their nearest MARC relator codes
+const COMIC_ROLES: [(&str, &str); 6] = [
+	("Penciller", "art"),
+	("Inker", "art"),
+	("Colorist", "clr"),
+	("CoverArtist", "cov"),
+	("Editor", "edt"),
+	("Translator", "trl"),
+];
+
+//What we want out of a comic archive: the ComicInfo.xml if there is one, and the first page to use as the cover
+struct ComicEntries {
+	comic_info: Option<String>,
+	cover_name: Option<String>,
+	cover: Option<Vec<u8>>,
+	pages: usize,
+}
+
+//A comic is just a pile of images in an archive, so everything beyond the cover comes from the ComicInfo.xml written by ComicRack and friends
+pub fn parse_comic(book_loc: &str, format: BookFormat, use_coverdir: bool) -> Result<ParsedBook, Box<dyn Error>> {
+	let entries = read_comic(book_loc, format, use_coverdir)?;
+
+	let mut bm = BookMetadata {
+		pages: if entries.pages > 0 { Some(entries.pages as i64) } else { None },
+		cover_mime: entries
+			.cover_name
+			.as_ref()
+			.and_then(|name| image_mime(name))
+			.map(|mime| mime.to_string()),
+		..Default::default()
+	};
+
+	if let Some(comic_info) = &entries.comic_info {
+		apply_comic_info(comic_info, &mut bm);
+	}
+
+	Ok((bm, entries.cover))
+}
+
+pub fn comic_cover(book_loc: &str, format: BookFormat) -> Option<(Vec<u8>, String)> {
+	let entries = read_comic(book_loc, format, true).ok()?;
+	let mime = image_mime(entries.cover_name.as_ref()?)?;
+	Some((entries.cover?, mime.to_string()))
+}
+
+fn read_comic(book_loc: &str, format: BookFormat, want_cover: bool) -> Result<ComicEntries, Box<dyn Error>> {
+	match format {
+		BookFormat::Cbr => read_cbr(book_loc, want_cover),
+		BookFormat::Cb7 => read_cb7(book_loc, want_cover),
+		BookFormat::Cbz => read_cbz(book_loc, want_cover),
+		_ => Err(Box::new(io::Error::other("Not a comic archive"))),
+	}
+}
+
+fn read_cbz(book_loc: &str, want_cover: bool) -> Result<ComicEntries, Box<dyn Error>> {
+	let mut archive = zip::ZipArchive::new(File::open(book_loc)?)?;
+	let names: Vec<String> = archive.file_names().map(|name| name.to_string()).collect();
+	let (info_name, cover_name, pages) = pick_entries(&names);
+
+	let comic_info = match info_name {
+		Some(name) => {
+			let mut xml = String::new();
+			archive.by_name(&name)?.read_to_string(&mut xml)?;
+			Some(xml)
+		}
+		None => None,
+	};
+
+	let cover = match &cover_name {
+		Some(name) if want_cover => {
+			let mut img = Vec::new();
+			archive.by_name(name)?.read_to_end(&mut img)?;
+			Some(img)
+		}
+		_ => None,
+	};
+
+	Ok(ComicEntries {
+		comic_info,
+		cover_name,
+		cover,
+		pages,
+	})
+}
+
+//unrar can only walk the archive front to back, so list it once to decide what we want then walk it again to extract
+fn read_cbr(book_loc: &str, want_cover: bool) -> Result<ComicEntries, Box<dyn Error>> {
+	let mut names = Vec::new();
+	for entry in unrar::Archive::new(book_loc).open_for_listing()? {
+		let entry = entry?;
+		if entry.is_file() {
+			names.push(entry.filename.to_string_lossy().to_string());
+		}
+	}
+	let (info_name, cover_name, pages) = pick_entries(&names);
+
+	let mut comic_info = None;
+	let mut cover = None;
+	let mut archive = unrar::Archive::new(book_loc).open_for_processing()?;
+	while let Some(header) = archive.read_header()? {
+		let name = header.entry().filename.to_string_lossy().to_string();
+		archive = if Some(&name) == info_name.as_ref() {
+			let (data, rest) = header.read()?;
+			comic_info = Some(String::from_utf8_lossy(&data).to_string());
+			rest
+		} else if want_cover && Some(&name) == cover_name.as_ref() {
+			let (data, rest) = header.read()?;
+			cover = Some(data);
+			rest
+		} else {
+			header.skip()?
+		};
+	}
+
+	Ok(ComicEntries {
+		comic_info,
+		cover_name,
+		cover,
+		pages,
+	})
+}
+
+fn read_cb7(book_loc: &str, want_cover: bool) -> Result<ComicEntries, Box<dyn Error>> {
+	let mut archive = sevenz_rust::SevenZReader::open(book_loc, sevenz_rust::Password::empty())?;
+	let names: Vec<String> = archive
+		.archive()
+		.files
+		.iter()
+		.filter(|entry| !entry.is_directory())
+		.map(|entry| entry.name().to_string())
+		.collect();
+	let (info_name, cover_name, pages) = pick_entries(&names);
+
+	let mut comic_info = None;
+	let mut cover = None;
+	//solid archives have to be decompressed in order regardless, so just pick out our entries as they go by
+	archive.for_each_entries(|entry, reader| {
+		if Some(entry.name()) == info_name.as_deref() {
+			let mut xml = String::new();
+			reader.read_to_string(&mut xml)?;
+			comic_info = Some(xml);
+		} else if want_cover && Some(entry.name()) == cover_name.as_deref() {
+			let mut img = Vec::new();
+			reader.read_to_end(&mut img)?;
+			cover = Some(img);
+		} else {
+			io::copy(reader, &mut io::sink())?;
+		}
+		Ok(true)
+	})?;
+
+	Ok(ComicEntries {
+		comic_info,
+		cover_name,
+		cover,
+		pages,
+	})
+}
+
+//Pages are ordered by file name, which is how every reader displays them. Returns the ComicInfo.xml entry, the cover entry and the page count.
+fn pick_entries(names: &[String]) -> (Option<String>, Option<String>, usize) {
+	let info_name = names
+		.iter()
+		.find(|name| {
+			Path::new(name)
+				.file_name()
+				.map(|file_name| file_name.to_string_lossy().to_ascii_lowercase() == COMIC_INFO)
+				.unwrap_or(false)
+		})
+		.cloned();
+
+	let mut images: Vec<&String> = names
+		.iter()
+		.filter(|name| !is_hidden_entry(name) && image_mime(name).is_some())
+		.collect();
+	images.sort();
+
+	(info_name, images.first().map(|name| name.to_string()), images.len())
+}
+
+//mac archivers like to leave __MACOSX/._001.jpg resource forks lying around, which would otherwise sort first
+fn is_hidden_entry(name: &str) -> bool {
+	name.split(['/', '\\'])
+		.any(|part| part.starts_with('.') || part == "__MACOSX")
+}
+
+fn image_mime(name: &str) -> Option<&'static str> {
+	let extension = Path::new(name).extension()?.to_str()?.to_ascii_lowercase();
+	match extension.as_str() {
+		"jpg" | "jpeg" => Some("image/jpeg"),
+		"png" => Some("image/png"),
+		"gif" => Some("image/gif"),
+		"webp" => Some("image/webp"),
+		"avif" => Some("image/avif"),
+		_ => None,
+	}
+}
+
+//ComicInfo.xml is flat - every property we want is a direct child of the ComicInfo element
+fn apply_comic_info(xml: &str, bm: &mut BookMetadata) {
+	let mut reader = Reader::from_str(xml);
+	reader.config_mut().trim_text(true);
+
+	let mut element = String::new();
+	let mut title = None;
+	let (mut year, mut month, mut day) = (None, None, None);
+	let mut page_count = None;
+	let mut writers = vec![];
+	let mut contributors = vec![];
+
+	loop {
+		match reader.read_event() {
+			Ok(Event::Start(e)) => element = String::from_utf8_lossy(e.name().as_ref()).to_string(),
+			Ok(Event::End(_)) => element.clear(),
+			Ok(Event::Text(t)) => {
+				let text = match t.unescape() {
+					Ok(text) => text.trim().to_string(),
+					Err(_) => continue,
+				};
+				if text.is_empty() {
+					continue;
+				}
+				match element.as_str() {
+					"Title" => title = Some(text),
+					"Series" => bm.series = Some(text),
+					"Number" => bm.issue = Some(text),
+					"Summary" => bm.description = Some(text),
+					"Writer" => writers.extend(split_credits(&text)),
+					"Publisher" => bm.publisher = Some(text),
+					"Genre" => bm.subject = Some(vec![text]),
+					"Year" => year = text.parse::<u16>().ok(),
+					"Month" => month = text.parse::<u8>().ok(),
+					"Day" => day = text.parse::<u8>().ok(),
+					"LanguageISO" => bm.language = Some(text),
+					"PageCount" => page_count = text.parse::<i64>().ok(),
+					_ => {
+						if let Some((_, role)) = COMIC_ROLES.iter().find(|(credit, _)| *credit == element) {
+							for name in split_credits(&text) {
+								let contributor = Contributor {
+									name,
+									role: Some(role.to_string()),
+								};
+								if !contributors.contains(&contributor) {
+									contributors.push(contributor);
+								}
+							}
+						}
+					}
+				}
+			}
+			Ok(Event::Eof) => break,
+			Err(e) => {
+				eprintln!("Error reading ComicInfo.xml:{}", e);
+				break;
+			}
+			_ => (),
+		}
+	}
+
+	//plenty of issues have no title of their own, so name them after their series
+	bm.title = match (title, &bm.series, &bm.issue) {
+		(Some(title), _, _) => Some(title),
+		(None, Some(series), Some(issue)) => Some(format!("{} #{}", series, issue)),
+		(None, Some(series), None) => Some(series.clone()),
+		(None, None, _) => None,
+	};
+
+	bm.pubdate = match (year, month, day) {
+		(Some(year), Some(month), Some(day)) => Some(format!("{:04}-{:02}-{:02}", year, month, day)),
+		(Some(year), Some(month), None) => Some(format!("{:04}-{:02}", year, month)),
+		(Some(year), None, _) => Some(format!("{:04}", year)),
+		_ => None,
+	}
+	.and_then(|date| parse_date(&date));
+
+	if page_count.is_some() {
+		bm.pages = page_count;
+	}
+	if !writers.is_empty() {
+		bm.creator = writers.first().cloned();
+		bm.creators = Some(writers);
+	}
+	if !contributors.is_empty() {
+		bm.contributors = Some(contributors);
+	}
+}
+
+//Every credit is a single comma separated list of names
+fn split_credits(credits: &str) -> Vec<String> {
+	credits
+		.split(',')
+		.map(|name| name.trim().to_string())
+		.filter(|name| !name.is_empty())
+		.collect()
+}
+
+#[test]
+fn test_comic_info() {
+	let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<ComicInfo xmlns:xsd="http://www.w3.org/2001/XMLSchema" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+  <Series>Saga</Series>
+  <Number>12</Number>
+  <Summary>The &amp; war goes on.</Summary>
+  <Year>2013</Year>
+  <Month>5</Month>
+  <Writer>Brian K. Vaughan</Writer>
+  <Penciller>Fiona Staples</Penciller>
+  <Inker>Fiona Staples</Inker>
+  <Letterer>Fonografiks</Letterer>
+  <Publisher>Image</Publisher>
+  <Genre>Science Fiction, Fantasy</Genre>
+  <PageCount>24</PageCount>
+  <Pages><Page Image="0" Type="FrontCover" /></Pages>
+</ComicInfo>"#;
+
+	let mut bm = BookMetadata::default();
+	apply_comic_info(xml, &mut bm);
+
+	assert_eq!(Some("Saga #12".to_string()), bm.title);
+	assert_eq!(Some("Saga".to_string()), bm.series);
+	assert_eq!(Some("12".to_string()), bm.issue);
+	assert_eq!(Some("The & war goes on.".to_string()), bm.description);
+	assert_eq!(Some("Brian K. Vaughan".to_string()), bm.creator);
+	assert_eq!(
+		Some(vec![Contributor {
+			name: "Fiona Staples".to_string(),
+			role: Some("art".to_string())
+		}]),
+		bm.contributors
+	);
+	assert_eq!(Some(vec!["Science Fiction, Fantasy".to_string()]), bm.subject);
+	assert_eq!(Some(time::macros::datetime!(2013-05-01 0:00 UTC)), bm.pubdate);
+	assert_eq!(Some(24), bm.pages);
+
+	let names = vec![
+		"__MACOSX/._000.jpg".to_string(),
+		"Saga 012/002.jpg".to_string(),
+		"Saga 012/001.JPG".to_string(),
+		"ComicInfo.xml".to_string(),
+	];
+	assert_eq!(
+		(Some("ComicInfo.xml".to_string()), Some("Saga 012/001.JPG".to_string()), 2),
+		pick_entries(&names)
+	);
+}
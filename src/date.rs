@@ -0,0 +1,103 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+//calibre writes 0101-01-01 when it doesn't know, and nothing we index was published before the printing press anyway
+const EARLIEST_YEAR: i32 = 1000;
+
+fn iso_date() -> &'static Regex {
+	static ISO_DATE: OnceLock<Regex> = OnceLock::new();
+	ISO_DATE.get_or_init(|| {
+		Regex::new(r"^(\d{4})(?:-(\d{1,2})(?:-(\d{1,2}))?)?(?:[T ](\d{1,2}):(\d{2})(?::(\d{2})(?:\.\d+)?)?\s*(Z|[+-]\d{2}:?\d{2})?)?$")
+			.unwrap()
+	})
+}
+
+//Dates in the wild are anything from "1887" through "March 15, 1887" to a full RFC 3339 timestamp. Whatever is missing
+//is filled in with the start of the period, so "1887" is 1887-01-01T00:00:00Z.
+pub fn parse_date(raw: &str) -> Option<OffsetDateTime> {
+	let raw = raw.trim();
+
+	let (year, month, day, time, offset) = match iso_date().captures(raw) {
+		Some(caps) => {
+			let num = |i: usize| caps.get(i).and_then(|m| m.as_str().parse::<u8>().ok());
+			let time = match (num(4), num(5)) {
+				(Some(hour), Some(minute)) => Time::from_hms(hour, minute, num(6).unwrap_or(0)).ok()?,
+				_ => Time::MIDNIGHT,
+			};
+			let offset = caps.get(7).map(|m| parse_offset(m.as_str())).unwrap_or(Some(UtcOffset::UTC))?;
+			(caps[1].parse::<i32>().ok()?, num(2).unwrap_or(1), num(3).unwrap_or(1), time, offset)
+		}
+		None => {
+			let (year, month, day) = parse_written_date(raw)?;
+			(year, month, day, Time::MIDNIGHT, UtcOffset::UTC)
+		}
+	};
+
+	if year < EARLIEST_YEAR {
+		return None;
+	}
+	let date = Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()?;
+	Some(PrimitiveDateTime::new(date, time).assume_offset(offset).to_offset(UtcOffset::UTC))
+}
+
+fn parse_offset(offset: &str) -> Option<UtcOffset> {
+	if offset == "Z" {
+		return Some(UtcOffset::UTC);
+	}
+	let sign = if offset.starts_with('-') { -1 } else { 1 };
+	let digits: String = offset.chars().filter(|c| c.is_ascii_digit()).collect();
+	let hours = digits.get(0..2)?.parse::<i8>().ok()?;
+	let minutes = digits.get(2..4)?.parse::<i8>().ok()?;
+	UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+//"March 1887", "15 March 1887", "Mar. 15, 1887" - or failing a month name, any year at all eg "c. 1887"
+fn parse_written_date(raw: &str) -> Option<(i32, u8, u8)> {
+	let words: Vec<&str> = raw.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).collect();
+
+	let year = words
+		.iter()
+		.find(|word| word.len() == 4 && word.chars().all(|c| c.is_ascii_digit()))?
+		.parse::<i32>()
+		.ok()?;
+
+	let month = words.iter().find_map(|word| {
+		let abbreviation: String = word.to_lowercase().chars().take(3).collect();
+		MONTHS.iter().position(|month| *month == abbreviation).map(|i| i as u8 + 1)
+	});
+
+	let day = match month {
+		Some(_) => words
+			.iter()
+			.find(|word| word.len() <= 2 && word.chars().all(|c| c.is_ascii_digit()))
+			.and_then(|word| word.parse::<u8>().ok()),
+		None => None,
+	};
+
+	Some((year, month.unwrap_or(1), day.unwrap_or(1)))
+}
+
+pub fn format_date(date: &OffsetDateTime) -> String {
+	date.format(&Rfc3339).unwrap_or_default()
+}
+
+#[test]
+fn test_parse_date() {
+	use time::macros::datetime;
+
+	assert_eq!(Some(datetime!(2019-01-15 4:52:30 UTC)), parse_date("2019-01-15T04:52:30Z"));
+	assert_eq!(Some(datetime!(2019-01-15 3:52:30 UTC)), parse_date("2019-01-15T04:52:30+01:00"));
+	assert_eq!(Some(datetime!(1887-01-01 0:00 UTC)), parse_date("1887"));
+	assert_eq!(Some(datetime!(1887-03-01 0:00 UTC)), parse_date("1887-03"));
+	assert_eq!(Some(datetime!(1887-03-01 0:00 UTC)), parse_date("March 1887"));
+	assert_eq!(Some(datetime!(1887-03-15 0:00 UTC)), parse_date("15 March 1887"));
+	assert_eq!(Some(datetime!(1887-03-15 0:00 UTC)), parse_date("Mar. 15, 1887"));
+	assert_eq!(Some(datetime!(1887-01-01 0:00 UTC)), parse_date("c. 1887"));
+	assert_eq!(None, parse_date("0101-01-01T00:00:00+00:00"));
+	assert_eq!(None, parse_date("unknown"));
+}
@@ -0,0 +1,312 @@
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+use base64::Engine;
+use encoding_rs::{Encoding, UTF_8};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::date::parse_date;
+use crate::scanner::BookFormat;
+use crate::{BookMetadata, Contributor, ParsedBook};
+
+pub const FB2_MIME: &str = "application/x-fictionbook+xml";
+pub const FB2_ZIP_MIME: &str = "application/x-zip-compressed-fb2";
+
+//FictionBook is a single XML document - metadata in description/title-info and publish-info, then the body, then any images as base64 binaries
+pub fn parse_fb2(book_loc: &str, format: BookFormat, use_coverdir: bool) -> Result<ParsedBook, Box<dyn Error>> {
+	let xml = read_fb2(book_loc, format)?;
+	let (bm, cover) = parse_fb2_xml(&xml, use_coverdir);
+	Ok((bm, cover))
+}
+
+pub fn fb2_cover(book_loc: &str, format: BookFormat) -> Option<(Vec<u8>, String)> {
+	let xml = read_fb2(book_loc, format).ok()?;
+	let (bm, cover) = parse_fb2_xml(&xml, true);
+	Some((cover?, bm.cover_mime?))
+}
+
+fn read_fb2(book_loc: &str, format: BookFormat) -> Result<String, Box<dyn Error>> {
+	let bytes = match format {
+		BookFormat::Fb2Zip => {
+			let mut archive = zip::ZipArchive::new(File::open(book_loc)?)?;
+			let name = archive
+				.file_names()
+				.find(|name| name.to_ascii_lowercase().ends_with(".fb2"))
+				.map(|name| name.to_string())
+				.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No .fb2 file in archive"))?;
+			let mut bytes = Vec::new();
+			archive.by_name(&name)?.read_to_end(&mut bytes)?;
+			bytes
+		}
+		_ => fs::read(book_loc)?,
+	};
+	Ok(decode_xml(&bytes))
+}
+
+//Much of the FB2 out there predates UTF-8 taking over, so honour the BOM or the XML declaration's encoding
+fn decode_xml(bytes: &[u8]) -> String {
+	if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+		return encoding.decode_without_bom_handling(&bytes[bom_len..]).0.into_owned();
+	}
+
+	let head = String::from_utf8_lossy(&bytes[..bytes.len().min(200)]);
+	let encoding = head
+		.find("encoding=")
+		.and_then(|pos| {
+			let quoted = &head[pos + 9..];
+			let quote = quoted.chars().next()?;
+			//the "quote" may be any character at all, even one several bytes long
+			let label = &quoted[quote.len_utf8()..];
+			let end = label.find(quote)?;
+			Encoding::for_label(&label.as_bytes()[..end])
+		})
+		.unwrap_or(UTF_8);
+
+	encoding.decode_without_bom_handling(bytes).0.into_owned()
+}
+
+fn parse_fb2_xml(xml: &str, want_cover: bool) -> ParsedBook {
+	let mut reader = Reader::from_str(xml);
+
+	let mut bm = BookMetadata::default();
+	let mut path: Vec<String> = vec![];
+	let mut genres = vec![];
+	let mut author_parts: Vec<(String, String)> = vec![];
+	let mut authors = vec![];
+	let mut contributors = vec![];
+	let mut annotation = String::new();
+	let mut cover_href = None;
+	let mut cover_base64: Option<String> = None;
+	let mut cover = None;
+
+	loop {
+		match reader.read_event() {
+			Ok(Event::Start(e)) => {
+				let name = local_name(&e);
+				if in_title_info(&path) && path.iter().any(|el| el == "annotation") {
+					annotation.push_str(&format!("<{}>", html_tag(&name)));
+				}
+				apply_attributes(&e, &name, &path, &mut bm, &mut cover_href);
+				if name == "binary" && cover_href.is_some() && attr(&e, "id") == cover_href {
+					bm.cover_mime = attr(&e, "content-type");
+					cover_base64 = Some(String::new());
+				}
+				path.push(name);
+			}
+			Ok(Event::Empty(e)) => {
+				let name = local_name(&e);
+				//an <empty-line/> between paragraphs is a line break
+				if in_title_info(&path) && path.iter().any(|el| el == "annotation") {
+					annotation.push_str(&format!("<{}/>", html_tag(&name)));
+				}
+				apply_attributes(&e, &name, &path, &mut bm, &mut cover_href);
+			}
+			Ok(Event::End(_)) => {
+				let name = path.pop().unwrap_or_default();
+				if !in_title_info(&path) {
+					if name == "binary" && cover_base64.is_some() {
+						let encoded: String = cover_base64.take().unwrap().split_whitespace().collect();
+						if want_cover {
+							cover = base64::engine::general_purpose::STANDARD.decode(encoded).ok();
+						}
+					}
+					continue;
+				}
+				if path.iter().any(|el| el == "annotation") {
+					annotation.push_str(&format!("</{}>", html_tag(&name)));
+				} else if (name == "author" || name == "translator") && path.last().map(|el| el == "title-info").unwrap_or(false) {
+					match (name.as_str(), author_name(&author_parts)) {
+						("author", Some(author)) => authors.push(author),
+						(_, Some(translator)) => contributors.push(Contributor {
+							name: translator,
+							role: Some("trl".to_string()),
+						}),
+						(_, None) => (),
+					}
+					author_parts.clear();
+				}
+			}
+			Ok(Event::Text(t)) => {
+				let text = match t.unescape() {
+					Ok(text) => text.to_string(),
+					Err(_) => continue,
+				};
+				if let Some(cover_base64) = cover_base64.as_mut() {
+					cover_base64.push_str(&text);
+					continue;
+				}
+				if in_title_info(&path) && path.iter().any(|el| el == "annotation") {
+					annotation.push_str(&escape(&text));
+					continue;
+				}
+
+				let text = text.trim().to_string();
+				if text.is_empty() {
+					continue;
+				}
+				let parent = path.iter().rev().nth(1).map(|el| el.as_str());
+				match (parent, path.last().map(|el| el.as_str())) {
+					(Some("title-info"), Some("genre")) => genres.push(text),
+					(Some("title-info"), Some("book-title")) => bm.title = Some(text),
+					(Some("title-info"), Some("lang")) => bm.language = Some(text),
+					(Some("title-info"), Some("date")) if bm.pubdate.is_none() => bm.pubdate = parse_date(&text),
+					(Some("author"), Some(part)) | (Some("translator"), Some(part)) if in_title_info(&path) => {
+						author_parts.push((part.to_string(), text))
+					}
+					(Some("publish-info"), Some("publisher")) => bm.publisher = Some(text),
+					(Some("publish-info"), Some("isbn")) => bm.isbn = Some(text),
+					(Some("publish-info"), Some("year")) if bm.pubdate.is_none() => bm.pubdate = parse_date(&text),
+					_ => (),
+				}
+			}
+			Ok(Event::Eof) => break,
+			Err(e) => {
+				eprintln!("Error reading FB2:{}", e);
+				break;
+			}
+			_ => (),
+		}
+	}
+
+	if !genres.is_empty() {
+		bm.subject = Some(genres);
+	}
+	if !authors.is_empty() {
+		bm.creator = authors.first().cloned();
+		bm.creators = Some(authors);
+	}
+	if !contributors.is_empty() {
+		bm.contributors = Some(contributors);
+	}
+	let annotation = annotation.trim();
+	if !annotation.is_empty() {
+		bm.description = Some(annotation.to_string());
+	}
+	(bm, cover)
+}
+
+fn apply_attributes(e: &BytesStart, name: &str, path: &[String], bm: &mut BookMetadata, cover_href: &mut Option<String>) {
+	if !in_title_info(path) {
+		return;
+	}
+	match name {
+		//sequences can nest for sub-series, the outermost is the one people know
+		"sequence" if bm.series.is_none() => {
+			bm.series = attr(e, "name");
+			bm.issue = attr(e, "number");
+		}
+		"date" => bm.pubdate = attr(e, "value").and_then(|date| parse_date(&date)),
+		"image" if path.last().map(|el| el == "coverpage").unwrap_or(false) && cover_href.is_none() => {
+			*cover_href = attr(e, "href").map(|href| href.trim_start_matches('#').to_string());
+		}
+		_ => (),
+	}
+}
+
+fn in_title_info(path: &[String]) -> bool {
+	path.iter().any(|el| el == "title-info")
+}
+
+//Elements and attributes come with whatever namespace prefix the producing tool liked, so match on local names
+fn local_name(e: &BytesStart) -> String {
+	String::from_utf8_lossy(e.local_name().as_ref()).to_string()
+}
+
+fn attr(e: &BytesStart, name: &str) -> Option<String> {
+	e.attributes()
+		.flatten()
+		.find(|attr| attr.key.local_name().as_ref() == name.as_bytes())
+		.and_then(|attr| attr.unescape_value().ok())
+		.map(|val| val.trim().to_string())
+		.filter(|val| !val.is_empty())
+}
+
+//Annotations are a small subset of FB2 markup that maps straight onto HTML
+fn html_tag(name: &str) -> &str {
+	match name {
+		"emphasis" => "em",
+		"strong" => "b",
+		"empty-line" => "br",
+		"subtitle" => "h5",
+		"cite" => "blockquote",
+		"p" => "p",
+		_ => "span",
+	}
+}
+
+fn author_name(parts: &[(String, String)]) -> Option<String> {
+	let part = |name: &str| parts.iter().find(|(part, _)| part == name).map(|(_, val)| val.as_str());
+	let full_name = [part("first-name"), part("middle-name"), part("last-name")]
+		.iter()
+		.flatten()
+		.cloned()
+		.collect::<Vec<&str>>()
+		.join(" ");
+	if full_name.is_empty() {
+		part("nickname").map(|nickname| nickname.to_string())
+	} else {
+		Some(full_name)
+	}
+}
+
+#[test]
+fn test_fb2() {
+	let xml = r##"<?xml version="1.0" encoding="windows-1251"?>
+<FictionBook xmlns="http://www.gribuser.ru/xml/fictionbook/2.0" xmlns:l="http://www.w3.org/1999/xlink">
+  <description>
+    <title-info>
+      <genre>sf_fantasy</genre>
+      <genre>adventure</genre>
+      <author><first-name>Сергей</first-name><last-name>Лукьяненко</last-name></author>
+      <translator><first-name>Andrew</first-name><last-name>Bromfield</last-name></translator>
+      <book-title>Ночной Дозор</book-title>
+      <annotation><p>Они — <emphasis>Иные</emphasis>.</p><empty-line/><p>Дозор.</p></annotation>
+      <date value="1998-01-01">1998</date>
+      <coverpage><image l:href="#cover.jpg"/></coverpage>
+      <lang>ru</lang>
+      <sequence name="Дозоры" number="1"/>
+    </title-info>
+    <document-info><author><nickname>scanner</nickname></author></document-info>
+    <publish-info><publisher>АСТ</publisher><isbn>5-237-01234-5</isbn></publish-info>
+  </description>
+  <body><section><p>Текст</p></section></body>
+  <binary id="cover.jpg" content-type="image/jpeg">
+    /9j/4AAQ
+  </binary>
+</FictionBook>"##;
+
+	let (encoded, _, _) = encoding_rs::WINDOWS_1251.encode(xml);
+	let (bm, cover) = parse_fb2_xml(&decode_xml(&encoded), true);
+
+	assert_eq!(Some("Ночной Дозор".to_string()), bm.title);
+	assert_eq!(Some("Сергей Лукьяненко".to_string()), bm.creator);
+	assert_eq!(
+		Some(vec![Contributor {
+			name: "Andrew Bromfield".to_string(),
+			role: Some("trl".to_string())
+		}]),
+		bm.contributors
+	);
+	assert_eq!(Some("<p>Они — <em>Иные</em>.</p><br/><p>Дозор.</p>".to_string()), bm.description);
+	assert_eq!(Some(vec!["sf_fantasy".to_string(), "adventure".to_string()]), bm.subject);
+	assert_eq!(Some(time::macros::datetime!(1998-01-01 0:00 UTC)), bm.pubdate);
+	assert_eq!(Some("ru".to_string()), bm.language);
+	assert_eq!(Some("Дозоры".to_string()), bm.series);
+	assert_eq!(Some("1".to_string()), bm.issue);
+	assert_eq!(Some("АСТ".to_string()), bm.publisher);
+	assert_eq!(Some("image/jpeg".to_string()), bm.cover_mime);
+	assert_eq!(Some(vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10]), cover);
+}
+
+#[test]
+fn test_decode_xml_odd_quote() {
+	//a quote character outside ASCII used to split the declaration mid-character
+	let xml = "<?xml version=\"1.0\" encoding=«utf-8«?><FictionBook/>";
+	assert_eq!(xml, decode_xml(xml.as_bytes()));
+	assert_eq!("<?xml encoding=»", decode_xml("<?xml encoding=»".as_bytes()));
+}
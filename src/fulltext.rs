@@ -0,0 +1,117 @@
+use std::io::{Read, Seek};
+
+use epub::doc::EpubDoc;
+
+//Tags that break up words - anything else (em, span, a...) can sit in the middle of one
+const BLOCK_TAGS: [&str; 20] = [
+	"p", "div", "br", "hr", "h1", "h2", "h3", "h4", "h5", "h6", "li", "ul", "ol", "tr", "td", "th", "blockquote", "section", "pre", "dd",
+];
+
+//The text of an epub in reading order, one spine item after another
+pub fn epub_text<R: Read + Seek>(doc: &mut EpubDoc<R>) -> String {
+	let mut text = String::new();
+	for page in 0..doc.get_num_pages() {
+		doc.set_current_page(page);
+		if let Some((xhtml, _)) = doc.get_current_str() {
+			text.push_str(&strip_markup(&xhtml));
+			text.push('\n');
+		}
+	}
+	text
+}
+
+//Not a real HTML parser, just enough to get indexable text out of XHTML: skip the head, scripts, styles and comments,
+//drop every tag and decode entities
+pub fn strip_markup(xhtml: &str) -> String {
+	let mut rest = match find_ignore_case(xhtml, "<body") {
+		Some(body) => &xhtml[body..],
+		None => xhtml,
+	};
+	let mut text = String::with_capacity(rest.len() / 2);
+
+	while let Some(lt) = rest.find('<') {
+		text.push_str(&decode_entities(&rest[..lt]));
+		rest = &rest[lt..];
+
+		if rest.starts_with("<!--") {
+			rest = rest.find("-->").map(|end| &rest[end + 3..]).unwrap_or("");
+			continue;
+		}
+
+		let tag_end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+		let name = rest[1..tag_end]
+			.trim_start_matches('/')
+			.split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+			.next()
+			.unwrap_or("")
+			.to_ascii_lowercase();
+		let self_closing = rest[..tag_end].ends_with("/>");
+		rest = &rest[tag_end..];
+
+		if (name == "script" || name == "style") && !self_closing {
+			let close = format!("</{}", name);
+			rest = find_ignore_case(rest, &close)
+				.and_then(|end| rest[end..].find('>').map(|gt| &rest[end + gt + 1..]))
+				.unwrap_or("");
+		} else if BLOCK_TAGS.contains(&name.as_str()) {
+			text.push(' ');
+		}
+	}
+	text.push_str(&decode_entities(rest));
+
+	text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+	haystack.to_ascii_lowercase().find(needle)
+}
+
+fn decode_entities(text: &str) -> String {
+	if !text.contains('&') {
+		return text.to_string();
+	}
+
+	let mut decoded = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(amp) = rest.find('&') {
+		decoded.push_str(&rest[..amp]);
+		rest = &rest[amp..];
+		let entity = rest.find(';').filter(|semi| *semi <= 10).map(|semi| &rest[1..semi]);
+		let ch = entity.and_then(|entity| match entity {
+			"amp" => Some('&'),
+			"lt" => Some('<'),
+			"gt" => Some('>'),
+			"quot" => Some('"'),
+			"apos" => Some('\''),
+			"nbsp" => Some(' '),
+			_ if entity.starts_with("#x") || entity.starts_with("#X") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+			_ if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then(char::from_u32),
+			_ => None,
+		});
+		match (ch, entity) {
+			(Some(ch), Some(entity)) => {
+				decoded.push(ch);
+				rest = &rest[entity.len() + 2..];
+			}
+			_ => {
+				decoded.push('&');
+				rest = &rest[1..];
+			}
+		}
+	}
+	decoded.push_str(rest);
+	decoded
+}
+
+#[test]
+fn test_strip_markup() {
+	let xhtml = r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Chapter I</title><style>p { margin: 0 }</style></head>
+<body><h2>Chapter I</h2><p>It was the <em>b</em>est of times,&#160;it was the worst&nbsp;of&#x20;times.</p><!-- a <p>comment</p> -->
+<script type="text/javascript">var x = "<p>";</script><p>Fish &amp; chips<br/>&unknown; &lt;done&gt;</p></body></html>"#;
+
+	assert_eq!(
+		"Chapter I It was the best of times, it was the worst of times. Fish & chips &unknown; <done>",
+		strip_markup(xhtml)
+	);
+}
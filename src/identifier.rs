@@ -0,0 +1,152 @@
+//Books carry identifiers in every shape imaginable - "urn:isbn:0-8044-2957-X", "ISBN 978 0 14 143956 3", bare uuids...
+//so classify and normalise them into "scheme:value" terms which can be matched exactly
+pub const ISBN: &str = "isbn";
+pub const UUID: &str = "uuid";
+pub const ASIN: &str = "asin";
+pub const DOI: &str = "doi";
+
+//The declared scheme is whatever the book said the identifier was (opf:scheme, a request path...), which is often missing or wrong
+pub fn classify(raw: &str, declared_scheme: Option<&str>) -> Option<String> {
+	let declared_scheme = declared_scheme
+		.map(|scheme| scheme.trim().to_ascii_lowercase())
+		.filter(|scheme| !scheme.is_empty());
+	let value = raw.trim();
+	if value.is_empty() {
+		return None;
+	}
+
+	match declared_scheme.as_deref() {
+		Some(ISBN) => return isbn(value).map(|isbn| term(ISBN, &isbn)),
+		Some(UUID) => return uuid(value).map(|uuid| term(UUID, &uuid)),
+		Some(DOI) => return doi(value).map(|doi| term(DOI, &doi)),
+		Some(ASIN) | Some("mobi-asin") | Some("amazon") => return asin(value, true).map(|asin| term(ASIN, &asin)),
+		_ => (),
+	}
+
+	if let Some(isbn) = isbn(value) {
+		Some(term(ISBN, &isbn))
+	} else if let Some(uuid) = uuid(value) {
+		Some(term(UUID, &uuid))
+	} else if let Some(doi) = doi(value) {
+		Some(term(DOI, &doi))
+	} else if let Some(asin) = asin(value, false) {
+		Some(term(ASIN, &asin))
+	} else {
+		//nothing we recognise, but if the book told us what it is (calibre ids, goodreads...) it may still be worth matching on
+		declared_scheme.map(|scheme| term(&scheme, value))
+	}
+}
+
+fn term(scheme: &str, value: &str) -> String {
+	format!("{}:{}", scheme, value)
+}
+
+fn strip_prefix_ignore_case<'a>(value: &'a str, prefixes: &[&str]) -> &'a str {
+	for prefix in prefixes {
+		if value.len() >= prefix.len() && value.is_char_boundary(prefix.len()) && value[..prefix.len()].eq_ignore_ascii_case(prefix) {
+			return value[prefix.len()..].trim();
+		}
+	}
+	value
+}
+
+//Always normalised to ISBN-13 so that a book is found whichever form was printed on the copy in hand
+pub fn isbn(value: &str) -> Option<String> {
+	let value = strip_prefix_ignore_case(value, &["urn:isbn:", "isbn:", "isbn-13", "isbn-10", "isbn"]);
+	let chars: Vec<char> = value
+		.chars()
+		.filter(|c| *c != '-' && *c != ' ' && *c != ':')
+		.map(|c| c.to_ascii_uppercase())
+		.collect();
+
+	match chars.len() {
+		10 if chars[..9].iter().all(|c| c.is_ascii_digit()) && (chars[9].is_ascii_digit() || chars[9] == 'X') => {
+			let digits: Vec<u32> = chars.iter().map(|c| c.to_digit(10).unwrap_or(10)).collect();
+			let sum: u32 = digits.iter().enumerate().map(|(i, d)| (10 - i as u32) * d).sum();
+			if !sum.is_multiple_of(11) {
+				return None;
+			}
+			let mut isbn13: Vec<u32> = vec![9, 7, 8];
+			isbn13.extend(&digits[..9]);
+			isbn13.push(isbn13_check_digit(&isbn13));
+			Some(isbn13.iter().map(|d| d.to_string()).collect())
+		}
+		13 if chars.iter().all(|c| c.is_ascii_digit()) => {
+			let digits: Vec<u32> = chars.iter().map(|c| c.to_digit(10).unwrap()).collect();
+			if !(digits[..3] == [9, 7, 8] || digits[..3] == [9, 7, 9]) || isbn13_check_digit(&digits[..12]) != digits[12] {
+				return None;
+			}
+			Some(chars.iter().collect())
+		}
+		_ => None,
+	}
+}
+
+fn isbn13_check_digit(digits: &[u32]) -> u32 {
+	let sum: u32 = digits.iter().enumerate().map(|(i, d)| if i % 2 == 0 { *d } else { d * 3 }).sum();
+	(10 - sum % 10) % 10
+}
+
+fn uuid(value: &str) -> Option<String> {
+	let value = strip_prefix_ignore_case(value, &["urn:uuid:", "uuid:"]).to_ascii_lowercase();
+	let groups: Vec<&str> = value.split('-').collect();
+	let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+	if lengths == [8, 4, 4, 4, 12] && groups.iter().all(|group| group.chars().all(|c| c.is_ascii_hexdigit())) {
+		Some(value)
+	} else {
+		None
+	}
+}
+
+//DOIs are case insensitive, and get written as links as often as not
+fn doi(value: &str) -> Option<String> {
+	let value = strip_prefix_ignore_case(
+		value,
+		&[
+			"https://doi.org/",
+			"http://doi.org/",
+			"https://dx.doi.org/",
+			"http://dx.doi.org/",
+			"urn:doi:",
+			"doi:",
+		],
+	);
+	match value.split_once('/') {
+		Some((prefix, suffix)) if prefix.starts_with("10.") && !suffix.is_empty() => Some(value.to_ascii_lowercase()),
+		_ => None,
+	}
+}
+
+//Without a declared scheme only the B0... form is distinctive enough to call an ASIN, the rest are ISBN-10s anyway
+fn asin(value: &str, declared: bool) -> Option<String> {
+	let value = strip_prefix_ignore_case(value, &["urn:asin:", "asin:"]).to_ascii_uppercase();
+	if value.len() != 10 || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+		return None;
+	}
+	if declared || value.starts_with("B0") {
+		Some(value)
+	} else {
+		None
+	}
+}
+
+#[test]
+fn test_classify() {
+	assert_eq!(Some("isbn:9780804429573".to_string()), classify("urn:isbn:0-8044-2957-x", None));
+	assert_eq!(Some("isbn:9780141439563".to_string()), classify("ISBN 978 0 14 143956 3", None));
+	assert_eq!(Some("isbn:9780141439563".to_string()), classify("0141439564", Some("ISBN")));
+	assert_eq!(None, classify("0141439565", Some("ISBN")));
+	assert_eq!(None, isbn("9780141439564"));
+	assert_eq!(
+		Some("uuid:3b8a3e8a-1c2d-4e5f-8a9b-0c1d2e3f4a5b".to_string()),
+		classify("urn:uuid:3B8A3E8A-1C2D-4E5F-8A9B-0C1D2E3F4A5B", None)
+	);
+	assert_eq!(
+		Some("doi:10.1000/xyz123".to_string()),
+		classify("https://doi.org/10.1000/XYZ123", None)
+	);
+	assert_eq!(Some("asin:B00K0OI42W".to_string()), classify("B00K0OI42W", None));
+	assert_eq!(Some("asin:0141439564".to_string()), classify("0141439564", Some("MOBI-ASIN")));
+	assert_eq!(Some("calibre:1234".to_string()), classify("1234", Some("calibre")));
+	assert_eq!(None, classify("1234", None));
+}
@@ -0,0 +1,114 @@
+//Books declare their language as "en", "en_US", "eng", "English" or worse, so normalise to BCP 47 ("en", "en-US", "pt-BR")
+//which is what OPDS and browsers expect. Only the commoner languages are known by their three letter code or name.
+
+//ISO 639-1, ISO 639-2/T, ISO 639-2/B where different, English name
+const LANGUAGES: [(&str, &str, &str, &str); 46] = [
+	("ar", "ara", "", "Arabic"),
+	("bg", "bul", "", "Bulgarian"),
+	("ca", "cat", "", "Catalan"),
+	("cs", "ces", "cze", "Czech"),
+	("cy", "cym", "wel", "Welsh"),
+	("da", "dan", "", "Danish"),
+	("de", "deu", "ger", "German"),
+	("el", "ell", "gre", "Greek"),
+	("en", "eng", "", "English"),
+	("eo", "epo", "", "Esperanto"),
+	("es", "spa", "", "Spanish"),
+	("et", "est", "", "Estonian"),
+	("eu", "eus", "baq", "Basque"),
+	("fa", "fas", "per", "Persian"),
+	("fi", "fin", "", "Finnish"),
+	("fr", "fra", "fre", "French"),
+	("ga", "gle", "", "Irish"),
+	("gd", "gla", "", "Gaelic"),
+	("he", "heb", "", "Hebrew"),
+	("hi", "hin", "", "Hindi"),
+	("hr", "hrv", "", "Croatian"),
+	("hu", "hun", "", "Hungarian"),
+	("id", "ind", "", "Indonesian"),
+	("is", "isl", "ice", "Icelandic"),
+	("it", "ita", "", "Italian"),
+	("ja", "jpn", "", "Japanese"),
+	("ko", "kor", "", "Korean"),
+	("la", "lat", "", "Latin"),
+	("lt", "lit", "", "Lithuanian"),
+	("lv", "lav", "", "Latvian"),
+	("nb", "nob", "", "Norwegian Bokmål"),
+	("nl", "nld", "dut", "Dutch"),
+	("nn", "nno", "", "Norwegian Nynorsk"),
+	("no", "nor", "", "Norwegian"),
+	("pl", "pol", "", "Polish"),
+	("pt", "por", "", "Portuguese"),
+	("ro", "ron", "rum", "Romanian"),
+	("ru", "rus", "", "Russian"),
+	("sk", "slk", "slo", "Slovak"),
+	("sl", "slv", "", "Slovenian"),
+	("sr", "srp", "", "Serbian"),
+	("sv", "swe", "", "Swedish"),
+	("th", "tha", "", "Thai"),
+	("tr", "tur", "", "Turkish"),
+	("uk", "ukr", "", "Ukrainian"),
+	("zh", "zho", "chi", "Chinese"),
+];
+
+pub fn normalise_language(raw: &str) -> Option<String> {
+	let raw = raw.trim();
+	if let Some((code, _, _, _)) = LANGUAGES.iter().find(|(_, _, _, name)| name.eq_ignore_ascii_case(raw)) {
+		return Some(code.to_string());
+	}
+
+	let mut subtags = raw.split(['-', '_']).filter(|subtag| !subtag.is_empty());
+	let primary = subtags.next()?.to_ascii_lowercase();
+	let primary = match primary.len() {
+		2 if primary.chars().all(|c| c.is_ascii_alphabetic()) => primary,
+		3 if primary.chars().all(|c| c.is_ascii_alphabetic()) => LANGUAGES
+			.iter()
+			.find(|(_, terminology, bibliographic, _)| *terminology == primary || *bibliographic == primary)
+			.map(|(code, _, _, _)| code.to_string())
+			.unwrap_or(primary), //a three letter code with no two letter equivalent is already valid BCP 47
+		_ => return None,
+	};
+	if primary == "un" || primary == "und" || primary == "zxx" {
+		return None;
+	}
+
+	//scripts are title case (zh-Hant), regions upper case (en-GB), anything else lower case
+	let mut tag = primary;
+	for subtag in subtags {
+		tag.push('-');
+		match subtag.len() {
+			4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+				tag.push_str(&subtag[..1].to_ascii_uppercase());
+				tag.push_str(&subtag[1..].to_ascii_lowercase());
+			}
+			2 | 3 => tag.push_str(&subtag.to_ascii_uppercase()),
+			_ => tag.push_str(&subtag.to_ascii_lowercase()),
+		}
+	}
+	Some(tag)
+}
+
+//"English", "English (United States)" would need a table of regions too, so just "English (en-US)"
+pub fn language_name(tag: &str) -> String {
+	let primary = tag.split('-').next().unwrap_or(tag);
+	match LANGUAGES.iter().find(|(code, _, _, _)| *code == primary) {
+		Some((_, _, _, name)) if primary == tag => name.to_string(),
+		Some((_, _, _, name)) => format!("{} ({})", name, tag),
+		None => tag.to_string(),
+	}
+}
+
+#[test]
+fn test_normalise_language() {
+	assert_eq!(Some("en".to_string()), normalise_language("en"));
+	assert_eq!(Some("en-US".to_string()), normalise_language("en_us"));
+	assert_eq!(Some("de".to_string()), normalise_language("ger"));
+	assert_eq!(Some("de".to_string()), normalise_language("deu"));
+	assert_eq!(Some("fr".to_string()), normalise_language("French"));
+	assert_eq!(Some("zh-Hant-TW".to_string()), normalise_language("zh-hant-tw"));
+	assert_eq!(Some("haw".to_string()), normalise_language("haw"));
+	assert_eq!(None, normalise_language("und"));
+	assert_eq!(None, normalise_language(""));
+	assert_eq!("German", language_name("de"));
+	assert_eq!("English (en-GB)", language_name("en-GB"));
+}
@@ -27,19 +27,30 @@ use server::Server;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fs;
 use std::hash::{Hash, Hasher};
-use std::io;
 use std::path::Path;
 use std::process;
 use time::OffsetDateTime;
 
 use crate::sqlite::Sqlite;
 
+mod comic;
+mod date;
 mod error;
+mod fb2;
+mod fulltext;
+mod identifier;
+mod language;
+mod mobi;
+mod opds2;
+mod opf;
+mod pdf;
 mod scanner;
 mod search_result;
 mod server;
 mod sqlite;
+mod suggest;
 mod test;
 mod ttvy;
 
@@ -52,16 +63,77 @@ pub struct BookMetadata {
 	title: Option<String>,
 	description: Option<String>,
 	publisher: Option<String>,
-	creator: Option<String>,
+	creator: Option<String>, //the first of the creators
+	creators: Option<Vec<String>>, //all the authors
 	subject: Option<Vec<String>>, //aka tags
 	#[serde(skip)]
 	file: String,
 	filesize: i64,
 	modtime: OffsetDateTime,
-	pubdate: Option<String>,
-	moddate: Option<String>,
+	#[serde(with = "time::serde::rfc3339::option")]
+	pubdate: Option<OffsetDateTime>,
+	#[serde(with = "time::serde::rfc3339::option")]
+	moddate: Option<OffsetDateTime>, //of the book's content, as opposed to modtime of the file
+	mime: String, //of the book file itself
+	pages: Option<i64>,
+	series: Option<String>,
+	issue: Option<String>, //number within the series, not always numeric eg "1.MU" or "Annual 2"
+	series_index: Option<f64>, //position in the series for reading order, where the number is numeric
+	contributors: Option<Vec<Contributor>>, //everyone else - editors, translators, illustrators...
+	isbn: Option<String>, //always ISBN-13
+	identifiers: Option<Vec<String>>, //as scheme:value eg "isbn:9780141439563", "uuid:..."
+	language: Option<String>, //BCP 47
+	#[serde(skip_serializing_if = "Option::is_none")]
+	snippet: Option<String>, //highlighted html showing why a search matched, only when asked for
 	cover_mime: Option<String>,
+	#[serde(skip)]
+	content_hash: Option<String>, //sha256 of the file, only known when freshly scanned
+	#[serde(skip)]
+	content: Option<String>, //the book's text, only extracted when indexing with --fulltext
+}
+
+//Format parsers fill in what they find and leave the rest to the defaults
+impl Default for BookMetadata {
+	fn default() -> BookMetadata {
+		BookMetadata {
+			id: 0,
+			title: None,
+			description: None,
+			publisher: None,
+			creator: None,
+			creators: None,
+			subject: None,
+			file: String::new(),
+			filesize: 0,
+			modtime: OffsetDateTime::UNIX_EPOCH,
+			pubdate: None,
+			moddate: None,
+			mime: "application/epub+zip".to_string(),
+			pages: None,
+			series: None,
+			issue: None,
+			series_index: None,
+			contributors: None,
+			isbn: None,
+			identifiers: None,
+			language: None,
+			snippet: None,
+			cover_mime: None,
+			content_hash: None,
+			content: None,
+		}
+	}
 }
+
+//What a format's parser finds - the book and, only if it is wanted for the cover dir, its cover image
+pub type ParsedBook = (BookMetadata, Option<Vec<u8>>);
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Contributor {
+	name: String,
+	role: Option<String>, //MARC relator code eg "edt", "trl", "ill"
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthorCount {
 	creator: String,
@@ -80,6 +152,18 @@ pub struct TagCount {
 	count: u32,
 }
 
+#[derive(Debug, Serialize)]
+pub struct LanguageCount {
+	language: String,
+	count: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SeriesCount {
+	series: String,
+	count: u32,
+}
+
 //A navigation category (primarily for opds)
 #[derive(Debug, Serialize)]
 pub struct OpdsCategory {
@@ -132,7 +216,7 @@ fn test_unmangle_tags() {
 		modtime: OffsetDateTime::now_utc(),
 		pubdate: None,
 		moddate: None,
-		cover_mime: None,
+		..Default::default()
 	};
 
 	let mut tagmap = HashMap::new();
@@ -175,13 +259,18 @@ fn test_unmangle_tags() {
 	assert_eq!(6, tagmap.len());
 }
 
+//Tags are counted, indexed and looked up in lower case, whatever script they are in
+pub fn normalise_tag(tag: &str) -> String {
+	tag.trim().to_lowercase()
+}
+
 impl BookMetadata {
 	pub fn add_tags(&self, tags: &mut HashMap<String, u32>) {
 		//add any known tags
 		if self.subject.is_some() {
 			for subject in self.subject.as_ref().unwrap() {
-				let subjectlc = subject.to_ascii_lowercase();
-				let subjectlc = subjectlc.trim();
+				let subjectlc = normalise_tag(subject);
+				let subjectlc = subjectlc.as_str();
 				let semi_count = subjectlc.matches(";").count();
 				let comma_count = subjectlc.matches(",").count();
 				let slash_count = subjectlc.matches("/").count();
@@ -225,6 +314,7 @@ impl BookMetadata {
 		}
 	}
 
+	//The id a book gets the first time it is seen. After that the id registry keeps it stable.
 	pub fn hash_md(&self) -> i64 {
 		let mut s = DefaultHasher::new();
 		self.hash(&mut s);
@@ -249,15 +339,35 @@ impl Hash for BookMetadata {
 	}
 }
 
+//What the index already knows about a book file, enough to tell whether it needs re-parsing
+#[derive(Debug)]
+pub struct IndexedFile {
+	id: i64,
+	filesize: i64,
+	modtime: OffsetDateTime,
+}
+
+impl IndexedFile {
+	//mtime is compared at second granularity as that is all tantivy's date field keeps
+	pub fn is_unchanged(&self, metadata: &fs::Metadata) -> bool {
+		let modtime: OffsetDateTime = metadata.modified().unwrap_or(std::time::UNIX_EPOCH).into();
+		self.filesize == metadata.len() as i64 && self.modtime.unix_timestamp() == modtime.unix_timestamp()
+	}
+}
+
 pub trait BookWriter {
 	fn write_epubs(&mut self, bms: &Vec<BookMetadata>) -> Result<(), Box<dyn Error>>;
+	//All files currently in the index, keyed by canonical path
+	fn indexed_files(&self) -> Result<HashMap<String, IndexedFile>, Box<dyn Error>>;
+	//Deletes the given books, returning what was stored for them so counts can be adjusted
+	fn remove_epubs(&mut self, ids: &[i64]) -> Result<Vec<BookMetadata>, Box<dyn Error>>;
 	fn commit(&mut self) -> Result<(), Box<dyn Error>>;
 }
 /// A fast OPDS server and epub indexer
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
-	/// Where the index is
+	/// Where the index is. Book ids are kept beside it in <dbFile>.ids.sqlite, which survives rebuilding the index
 	#[arg(short, long, default_value = ".shelfcontrol")]
 	dbFile: String,
 
@@ -280,6 +390,10 @@ enum Command {
 		/// Hostname to bind to
 		#[arg(short, long, default_value = "localhost")]
 		host: String,
+
+		/// How many entries each page of an OPDS feed has. Readers on slow devices may want fewer.
+		#[arg(long, default_value_t = 50)]
+		page_size: usize,
 	},
 
 	/// Run the indexer
@@ -287,6 +401,18 @@ enum Command {
 		/// Which directories to scan for books. Multiple directories can be specified.
 		#[arg(short, long, default_value = ".", num_args=1.., value_parser)]
 		dir: Vec<String>,
+
+		/// Update an existing index rather than building a new one. Only new or changed files are parsed, and books whose files are gone are removed.
+		#[arg(long)]
+		incremental: bool,
+
+		/// After scanning, keep running and update the index as books are added, changed or removed.
+		#[arg(long)]
+		watch: bool,
+
+		/// Also index the full text of each epub so it can be searched with content:"some quotation". Makes the index much larger. Pass it again with --incremental or --watch to keep new books searchable.
+		#[arg(long)]
+		fulltext: bool,
 	},
 }
 
@@ -308,34 +434,56 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 	};
 
 	match cli.command {
-		Command::Serve { port, host } => {
-			start_server(db_dir, port, host, coverdir, use_coverdir);
+		Command::Serve { port, host, page_size } => {
+			start_server(db_dir, port, host, page_size, coverdir, use_coverdir);
 		}
-		Command::Index { dir } => {
-			start_indexer(db_dir, dir, coverdir, use_coverdir);
+		Command::Index {
+			dir,
+			incremental,
+			watch,
+			fulltext,
+		} => {
+			let options = scanner::ScanOptions {
+				coverdir,
+				use_coverdir,
+				incremental,
+				fulltext,
+			};
+			start_indexer(db_dir, dir, options, watch);
 		}
 	}
 
 	Ok(())
 }
 
-fn start_indexer(db_dir: String, dirs: Vec<String>, coverdir: String, use_coverdir: bool) {
-	let writer: Box<dyn BookWriter + Sync + Send> = match ttvy::TantivyWriter::new(&db_dir) {
-		Ok(writer) => Ok(Box::new(writer)),
-		Err(_) => Err(Box::new(io::Error::new(io::ErrorKind::Other, "TantivyError:"))),
-	}
-	.expect("Could not create indexer, is the db directory writeable?");
+fn start_indexer(db_dir: String, dirs: Vec<String>, options: scanner::ScanOptions, watch: bool) {
+	let writer = if options.incremental {
+		ttvy::TantivyWriter::open(&db_dir)
+	} else {
+		ttvy::TantivyWriter::new(&db_dir)
+	};
+	let mut writer: Box<dyn BookWriter + Sync + Send> = match writer {
+		Ok(writer) => Box::new(writer),
+		Err(e) => {
+			eprintln!("Could not create indexer: {}", e);
+			process::exit(3);
+		}
+	};
 
-	let sqlite = Sqlite::new(&format!("{}/counts.sqlite", &db_dir)).expect("Could not create sqlite db.");
-	scanner::scan_dirs(dirs, coverdir, use_coverdir, writer, sqlite);
+	let sqlite = Sqlite::new(&db_dir).expect("Could not create sqlite db.");
+	scanner::scan_dirs(&dirs, &options, writer.as_mut(), &sqlite);
+
+	if watch {
+		scanner::watch_dirs(&dirs, &options, writer.as_mut(), &sqlite).expect("Could not watch directories for changes.");
+	}
 }
 
-fn start_server(db_dir: String, port: u16, host: String, coverdir: String, use_coverdir: bool) {
-	let sqlite =
-		Sqlite::new(&format!("{}/counts.sqlite", &db_dir)).expect("Could not open sqlite db. Check dbFile directory is writeable.");
+fn start_server(db_dir: String, port: u16, host: String, page_size: usize, coverdir: String, use_coverdir: bool) {
+	let sqlite = Sqlite::new(&db_dir).expect("Could not open sqlite db. Check dbFile directory is writeable.");
 	match ttvy::TantivyReader::new(db_dir) {
 		Ok(reader) => {
-			let server = Server::new(reader, sqlite, host, port, use_coverdir, coverdir);
+			let server =
+				Server::new(reader, sqlite, host, port, page_size, use_coverdir, coverdir).expect("Could not build suggestions from the index.");
 			server.serve().expect("Could not start server. Is port already bound?");
 		}
 		Err(e) => panic!("Could not read given index: {}", e),
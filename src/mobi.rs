@@ -0,0 +1,298 @@
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::date::parse_date;
+use crate::identifier;
+use crate::{BookMetadata, ParsedBook};
+
+pub const MOBI_MIME: &str = "application/x-mobipocket-ebook";
+pub const AZW3_MIME: &str = "application/x-mobi8-ebook";
+
+//EXTH record types, see https://wiki.mobileread.com/wiki/MOBI#EXTH_Header
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_ISBN: u32 = 104;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_PUBDATE: u32 = 106;
+const EXTH_ASIN: u32 = 113;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_UPDATED_TITLE: u32 = 503;
+const EXTH_CDE_ASIN: u32 = 504; //usually the same ASIN again, but personal documents only have this one
+const EXTH_LANGUAGE: u32 = 524;
+
+const NO_IMAGE: u32 = 0xFFFFFFFF;
+
+//A Palm database is a header, a table of record offsets, then the records themselves
+struct PalmDb {
+	file: File,
+	offsets: Vec<u64>,
+	len: u64,
+}
+
+impl PalmDb {
+	fn open(book_loc: &str) -> Result<PalmDb, Box<dyn Error>> {
+		let mut file = File::open(book_loc)?;
+		let len = file.metadata()?.len();
+
+		let mut header = [0u8; 78];
+		file.read_exact(&mut header)?;
+		if &header[60..68] != b"BOOKMOBI" {
+			return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Not a MOBI file")));
+		}
+
+		let record_count = u16::from_be_bytes([header[76], header[77]]) as usize;
+		let mut record_list = vec![0u8; record_count * 8];
+		file.read_exact(&mut record_list)?;
+		let offsets = record_list
+			.chunks_exact(8)
+			.map(|entry| u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]) as u64)
+			.collect();
+
+		Ok(PalmDb { file, offsets, len })
+	}
+
+	fn record(&mut self, index: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+		let start = *self
+			.offsets
+			.get(index)
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "MOBI record out of range"))?;
+		let end = self.offsets.get(index + 1).copied().unwrap_or(self.len);
+		if end < start || end > self.len {
+			return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "Corrupt MOBI record table")));
+		}
+
+		let mut record = vec![0u8; (end - start) as usize];
+		self.file.seek(SeekFrom::Start(start))?;
+		self.file.read_exact(&mut record)?;
+		Ok(record)
+	}
+}
+
+//What record 0 tells us: the PalmDOC and MOBI headers followed by the EXTH metadata block
+struct MobiHeader {
+	full_name: Option<String>,
+	first_image: u32,
+	exth: Vec<(u32, Vec<u8>)>,
+	utf8: bool,
+}
+
+impl MobiHeader {
+	fn parse(record0: &[u8]) -> Result<MobiHeader, Box<dyn Error>> {
+		let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "Corrupt MOBI header");
+		if record0.get(16..20) != Some(b"MOBI") {
+			return Err(Box::new(corrupt()));
+		}
+
+		let header_len = be_u32(record0, 20).ok_or_else(corrupt)? as usize;
+		let utf8 = be_u32(record0, 28) == Some(65001);
+		let first_image = be_u32(record0, 108).unwrap_or(NO_IMAGE);
+		let exth_flags = be_u32(record0, 128).unwrap_or(0);
+
+		let full_name = match (be_u32(record0, 84), be_u32(record0, 88)) {
+			(Some(offset), Some(len)) => offset
+				.checked_add(len)
+				.and_then(|end| record0.get(offset as usize..end as usize))
+				.map(|name| decode(name, utf8)),
+			_ => None,
+		};
+
+		let mut exth = Vec::new();
+		let exth_start = 16 + header_len;
+		if exth_flags & 0x40 != 0 && record0.get(exth_start..exth_start + 4) == Some(b"EXTH") {
+			let count = be_u32(record0, exth_start + 8).ok_or_else(corrupt)?;
+			let mut pos = exth_start + 12;
+			for _ in 0..count {
+				let (rec_type, rec_len) = match (be_u32(record0, pos), be_u32(record0, pos + 4)) {
+					(Some(rec_type), Some(rec_len)) if rec_len >= 8 => (rec_type, rec_len as usize),
+					_ => break,
+				};
+				match record0.get(pos + 8..pos + rec_len) {
+					Some(data) => exth.push((rec_type, data.to_vec())),
+					None => break,
+				}
+				pos += rec_len;
+			}
+		}
+
+		Ok(MobiHeader {
+			full_name,
+			first_image,
+			exth,
+			utf8,
+		})
+	}
+
+	fn exth_str(&self, rec_type: u32) -> Option<String> {
+		self.exth_strs(rec_type).into_iter().next()
+	}
+
+	fn exth_strs(&self, rec_type: u32) -> Vec<String> {
+		self.exth
+			.iter()
+			.filter(|(t, _)| *t == rec_type)
+			.map(|(_, data)| decode(data, self.utf8))
+			.filter(|val| !val.is_empty())
+			.collect()
+	}
+
+	//The cover is given as an offset from the first image record
+	fn cover_record(&self) -> Option<usize> {
+		let offset = self
+			.exth
+			.iter()
+			.find(|(t, _)| *t == EXTH_COVER_OFFSET)
+			.and_then(|(_, data)| be_u32(data, 0))?;
+		if self.first_image == NO_IMAGE || offset == NO_IMAGE {
+			return None;
+		}
+		Some(self.first_image.checked_add(offset)? as usize)
+	}
+
+	fn apply(&self, bm: &mut BookMetadata) {
+		bm.title = self.exth_str(EXTH_UPDATED_TITLE).or_else(|| self.full_name.clone());
+		let authors = self.exth_strs(EXTH_AUTHOR);
+		if !authors.is_empty() {
+			bm.creator = authors.first().cloned();
+			bm.creators = Some(authors);
+		}
+		bm.publisher = self.exth_str(EXTH_PUBLISHER);
+		bm.description = self.exth_str(EXTH_DESCRIPTION);
+		bm.isbn = self.exth_str(EXTH_ISBN);
+		bm.pubdate = self.exth_str(EXTH_PUBDATE).and_then(|date| parse_date(&date));
+		bm.language = self.exth_str(EXTH_LANGUAGE);
+
+		let mut identifiers = vec![];
+		for asin in self.exth_strs(EXTH_ASIN).into_iter().chain(self.exth_strs(EXTH_CDE_ASIN)) {
+			if let Some(asin) = identifier::classify(&asin, Some(identifier::ASIN)) {
+				if !identifiers.contains(&asin) {
+					identifiers.push(asin);
+				}
+			}
+		}
+		if !identifiers.is_empty() {
+			bm.identifiers = Some(identifiers);
+		}
+
+		let subjects = self.exth_strs(EXTH_SUBJECT);
+		if !subjects.is_empty() {
+			bm.subject = Some(subjects);
+		}
+	}
+}
+
+//Kindle formats all share the Palm database layout - KF8 (azw3) books are the same headers with a newer version number
+pub fn parse_mobi(book_loc: &str, use_coverdir: bool) -> Result<ParsedBook, Box<dyn Error>> {
+	let mut db = PalmDb::open(book_loc)?;
+	let header = MobiHeader::parse(&db.record(0)?)?;
+
+	let mut bm = BookMetadata::default();
+	header.apply(&mut bm);
+
+	let cover = header.cover_record().and_then(|index| db.record(index).ok());
+	bm.cover_mime = cover.as_ref().and_then(|cover| image_mime(cover)).map(|mime| mime.to_string());
+
+	Ok((bm, if use_coverdir { cover } else { None }))
+}
+
+pub fn mobi_cover(book_loc: &str) -> Option<(Vec<u8>, String)> {
+	let mut db = PalmDb::open(book_loc).ok()?;
+	let header = MobiHeader::parse(&db.record(0).ok()?).ok()?;
+	let cover = db.record(header.cover_record()?).ok()?;
+	let mime = image_mime(&cover)?;
+	Some((cover, mime.to_string()))
+}
+
+//Image records carry no type, so sniff the magic numbers
+fn image_mime(img: &[u8]) -> Option<&'static str> {
+	if img.starts_with(&[0xFF, 0xD8, 0xFF]) {
+		Some("image/jpeg")
+	} else if img.starts_with(&[0x89, b'P', b'N', b'G']) {
+		Some("image/png")
+	} else if img.starts_with(b"GIF8") {
+		Some("image/gif")
+	} else {
+		None
+	}
+}
+
+fn be_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+	bytes.get(pos..pos + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+//Older books are CP1252, anything recent is UTF-8
+fn decode(bytes: &[u8], utf8: bool) -> String {
+	let decoded = if utf8 {
+		String::from_utf8_lossy(bytes).to_string()
+	} else {
+		encoding_rs::WINDOWS_1252.decode(bytes).0.to_string()
+	};
+	decoded.trim_matches(|c: char| c.is_whitespace() || c == '\0').to_string()
+}
+
+#[test]
+fn test_mobi_header() {
+	let exth_records: Vec<(u32, &[u8])> = vec![
+		(EXTH_AUTHOR, b"Charles Dickens"),
+		(EXTH_AUTHOR, b"Wilkie Collins"),
+		(EXTH_SUBJECT, b"Fiction"),
+		(EXTH_SUBJECT, b"Classics"),
+		(EXTH_ISBN, b"9780141439563"),
+		(EXTH_ASIN, b"B000JQU1VS"),
+		(EXTH_CDE_ASIN, b"B000JQU1VS"),
+		(EXTH_UPDATED_TITLE, b"Bleak House"),
+		(EXTH_COVER_OFFSET, &[0, 0, 0, 2]),
+	];
+	let mut exth = b"EXTH".to_vec();
+	let exth_len = 12 + exth_records.iter().map(|(_, data)| 8 + data.len()).sum::<usize>();
+	exth.extend((exth_len as u32).to_be_bytes());
+	exth.extend((exth_records.len() as u32).to_be_bytes());
+	for (rec_type, data) in exth_records {
+		exth.extend(rec_type.to_be_bytes());
+		exth.extend((8 + data.len() as u32).to_be_bytes());
+		exth.extend(data);
+	}
+
+	let mut record0 = vec![0u8; 16 + 232];
+	record0[16..20].copy_from_slice(b"MOBI");
+	record0[20..24].copy_from_slice(&232u32.to_be_bytes());
+	record0[28..32].copy_from_slice(&65001u32.to_be_bytes());
+	record0[108..112].copy_from_slice(&5u32.to_be_bytes());
+	record0[128..132].copy_from_slice(&0x50u32.to_be_bytes());
+	record0.extend(&exth);
+	let name_offset = record0.len() as u32;
+	record0.extend(b"BLEAK_HOUSE");
+	record0[84..88].copy_from_slice(&name_offset.to_be_bytes());
+	record0[88..92].copy_from_slice(&11u32.to_be_bytes());
+
+	let header = MobiHeader::parse(&record0).unwrap();
+	let mut bm = BookMetadata::default();
+	header.apply(&mut bm);
+
+	assert_eq!(Some("Bleak House".to_string()), bm.title);
+	assert_eq!(Some("BLEAK_HOUSE".to_string()), header.full_name);
+	assert_eq!(Some("Charles Dickens".to_string()), bm.creator);
+	assert_eq!(Some(vec!["Charles Dickens".to_string(), "Wilkie Collins".to_string()]), bm.creators);
+	assert_eq!(Some("9780141439563".to_string()), bm.isbn);
+	assert_eq!(Some(vec!["asin:B000JQU1VS".to_string()]), bm.identifiers);
+	assert_eq!(Some(vec!["Fiction".to_string(), "Classics".to_string()]), bm.subject);
+	assert_eq!(Some(7), header.cover_record());
+}
+
+#[test]
+fn test_mobi_header_overflow() {
+	//offsets from a corrupt file are not to be trusted to add up
+	let mut record0 = vec![0u8; 16 + 232];
+	record0[16..20].copy_from_slice(b"MOBI");
+	record0[20..24].copy_from_slice(&232u32.to_be_bytes());
+	record0[84..88].copy_from_slice(&u32::MAX.to_be_bytes());
+	record0[88..92].copy_from_slice(&u32::MAX.to_be_bytes());
+	record0[108..112].copy_from_slice(&(u32::MAX - 1).to_be_bytes());
+
+	let mut header = MobiHeader::parse(&record0).unwrap();
+	assert_eq!(None, header.full_name);
+	header.exth.push((EXTH_COVER_OFFSET, 2u32.to_be_bytes().to_vec()));
+	assert_eq!(None, header.cover_record());
+}
@@ -0,0 +1,295 @@
+use crate::date::format_date;
+use crate::search_result::{OpdsPage, SearchResult};
+use crate::{BookMetadata, OpdsCategory};
+
+pub const OPDS_JSON: &str = "application/opds+json";
+
+//An OPDS 2.0 feed (https://drafts.opds.io/opds-2.0), the JSON sibling of the Atom feeds. A feed is navigation, publications,
+//or both in groups, and anything not given is left out rather than sent empty.
+#[derive(Debug, Serialize)]
+pub struct Feed {
+	pub metadata: FeedMetadata,
+	pub links: Vec<Link>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub navigation: Vec<Link>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub publications: Vec<Publication>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub facets: Vec<Group>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub groups: Vec<Group>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedMetadata {
+	pub title: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub number_of_items: Option<usize>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub items_per_page: Option<usize>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub current_page: Option<usize>, //counting from 1
+}
+
+impl FeedMetadata {
+	pub fn new(title: &str) -> FeedMetadata {
+		FeedMetadata {
+			title: title.to_string(),
+			number_of_items: None,
+			items_per_page: None,
+			current_page: None,
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+pub struct Link {
+	pub href: String,
+	#[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+	pub mime: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub rel: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub title: Option<String>,
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	pub templated: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub properties: Option<LinkProperties>,
+}
+
+impl Link {
+	pub fn new(rel: &str, href: String, mime: &str) -> Link {
+		Link {
+			href,
+			mime: Some(mime.to_string()),
+			rel: Some(rel.to_string()),
+			title: None,
+			templated: false,
+			properties: None,
+		}
+	}
+
+	//To another feed of this catalog, as listed in navigation, facets and groups
+	pub fn to_feed(title: String, href: String, number_of_items: Option<u64>) -> Link {
+		Link {
+			href,
+			mime: Some(OPDS_JSON.to_string()),
+			rel: None,
+			title: Some(title),
+			templated: false,
+			properties: number_of_items.map(|number_of_items| LinkProperties { number_of_items }),
+		}
+	}
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkProperties {
+	pub number_of_items: u64,
+}
+
+//Facets and groups are both a title and some links, groups may have publications instead
+#[derive(Debug, Serialize)]
+pub struct Group {
+	pub metadata: FeedMetadata,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub links: Vec<Link>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub navigation: Vec<Link>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub publications: Vec<Publication>,
+}
+
+//A book, described with the Readium Web Publication Manifest metadata
+#[derive(Debug, Serialize)]
+pub struct Publication {
+	pub metadata: PublicationMetadata,
+	pub links: Vec<Link>,
+	pub images: Vec<Link>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicationMetadata {
+	#[serde(rename = "@type")]
+	pub kind: &'static str,
+	pub identifier: String,
+	pub title: String,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub author: Vec<Contributor>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub contributor: Vec<Contributor>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub publisher: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub language: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub published: Option<String>,
+	pub modified: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub description: Option<String>,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub subject: Vec<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub number_of_pages: Option<i64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub belongs_to: Option<BelongsTo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Contributor {
+	pub name: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub role: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BelongsTo {
+	pub series: Vec<Series>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Series {
+	pub name: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub position: Option<f64>,
+}
+
+impl Publication {
+	//feeds is where this catalog's feeds are, for the link to similar books
+	pub fn new(book: &BookMetadata, feeds: &str) -> Publication {
+		//the isbn is the better identifier where there is one, otherwise our own id
+		let identifier = match &book.isbn {
+			Some(isbn) => format!("urn:isbn:{}", isbn),
+			None => format!("urn:shelfcontrol:{}", book.id),
+		};
+		let mut images = vec![];
+		if let Some(cover_mime) = &book.cover_mime {
+			images.push(Link::new("http://opds-spec.org/image", format!("/img/{}", book.id), cover_mime));
+		}
+
+		Publication {
+			metadata: PublicationMetadata {
+				kind: "http://schema.org/Book",
+				identifier,
+				title: book.title.clone().unwrap_or_default(),
+				author: book
+					.creators
+					.iter()
+					.flatten()
+					.map(|name| Contributor {
+						name: name.clone(),
+						role: None,
+					})
+					.collect(),
+				contributor: book
+					.contributors
+					.iter()
+					.flatten()
+					.map(|contributor| Contributor {
+						name: contributor.name.clone(),
+						role: contributor.role.clone(),
+					})
+					.collect(),
+				publisher: book.publisher.clone(),
+				language: book.language.clone(),
+				published: book.pubdate.as_ref().map(format_date),
+				modified: format_date(book.moddate.as_ref().unwrap_or(&book.modtime)),
+				description: book.description.clone(),
+				subject: book.subject.clone().unwrap_or_default(),
+				number_of_pages: book.pages,
+				belongs_to: book.series.as_ref().map(|series| BelongsTo {
+					series: vec![Series {
+						name: series.clone(),
+						position: book.series_index,
+					}],
+				}),
+			},
+			links: vec![
+				Link::new("http://opds-spec.org/acquisition", format!("/api/book/{}", book.id), &book.mime),
+				Link::new("related", format!("{}/similar/{}", feeds, book.id), OPDS_JSON),
+			],
+			images,
+		}
+	}
+}
+
+impl Feed {
+	//The same page of the catalog as the Atom feed gives. feeds is where this catalog's feeds are.
+	pub fn new(page: &OpdsPage, result: &Option<SearchResult<BookMetadata>>, navs: &Option<Vec<OpdsCategory>>, feeds: &str) -> Feed {
+		let mut links = vec![
+			Link::new("self", page.url.clone(), OPDS_JSON),
+			Link::new("start", feeds.to_string(), OPDS_JSON),
+			Link {
+				templated: true,
+				..Link::new("search", format!("{}/books{{?query}}", feeds), OPDS_JSON)
+			},
+		];
+		links.extend(page.links.iter().map(|link| Link::new(&link.rel, link.href.clone(), OPDS_JSON)));
+
+		//facets of the same group go together, in the order they come
+		let mut facets: Vec<Group> = vec![];
+		for facet in &page.facets {
+			if facets.last().map(|group| &group.metadata.title) != Some(&facet.group) {
+				facets.push(Group {
+					metadata: FeedMetadata::new(&facet.group),
+					links: vec![],
+					navigation: vec![],
+					publications: vec![],
+				});
+			}
+			let mut link = Link::to_feed(facet.title.clone(), facet.href.clone(), facet.count);
+			if facet.active {
+				link.rel = Some("self".to_string());
+			}
+			facets.last_mut().unwrap().links.push(link);
+		}
+
+		Feed {
+			metadata: FeedMetadata {
+				title: page.title.clone(),
+				number_of_items: Some(page.total_results),
+				items_per_page: Some(page.items_per_page),
+				current_page: Some((page.start_index - 1) / page.items_per_page + 1),
+			},
+			links,
+			navigation: navs.iter().flatten().map(Link::from).collect(),
+			publications: result
+				.iter()
+				.flat_map(|result| result.payload.iter())
+				.map(|book| Publication::new(book, feeds))
+				.collect(),
+			facets,
+			groups: vec![],
+		}
+	}
+}
+
+impl From<&OpdsCategory> for Link {
+	fn from(nav: &OpdsCategory) -> Link {
+		Link::to_feed(nav.title.clone(), nav.url.clone(), None)
+	}
+}
+
+#[test]
+fn test_publication() {
+	let book = BookMetadata {
+		id: 42,
+		title: Some("Hard Times".to_string()),
+		creators: Some(vec!["Charles Dickens".to_string()]),
+		mime: "application/epub+zip".to_string(),
+		series: Some("Novels".to_string()),
+		series_index: Some(10.0),
+		cover_mime: Some("image/jpeg".to_string()),
+		..Default::default()
+	};
+	let json = serde_json::to_value(Publication::new(&book, "/opds/v2")).unwrap();
+	assert_eq!("urn:shelfcontrol:42", json["metadata"]["identifier"]);
+	assert_eq!("Charles Dickens", json["metadata"]["author"][0]["name"]);
+	assert_eq!(10.0, json["metadata"]["belongsTo"]["series"][0]["position"]);
+	assert_eq!("/api/book/42", json["links"][0]["href"]);
+	assert_eq!("/opds/v2/similar/42", json["links"][1]["href"]);
+	assert_eq!("/img/42", json["images"][0]["href"]);
+	assert!(json["metadata"].get("publisher").is_none());
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::Contributor;
+
+//Relator codes for whoever made the file rather than the book - calibre marks itself as one
+const BOOK_PRODUCER: &str = "bkp";
+const AUTHOR: &str = "aut";
+
+//What epub-rs doesn't give us from the package document - mostly anything expressed with EPUB3 refinements,
+//which it flattens into plain key/values and so loses what they refine
+#[derive(Debug, Default)]
+pub struct OpfMetadata {
+	pub series: Option<String>,
+	pub series_index: Option<f64>,
+	pub identifiers: Vec<(Option<String>, String)>, //with the opf:scheme, if it declared one
+	pub authors: Vec<String>,
+	pub contributors: Vec<Contributor>,
+	pub pubdate: Option<String>,
+	pub moddate: Option<String>,
+}
+
+//A dc:creator or dc:contributor, whose role is either an opf:role attribute or an EPUB3 refinement
+struct Person {
+	creator: bool,
+	id: Option<String>,
+	role: Option<String>,
+	name: String,
+}
+
+//An EPUB3 meta element whose text is its value
+struct PropertyMeta {
+	id: Option<String>,
+	property: String,
+	refines: Option<String>,
+}
+
+pub fn parse_opf(xml: &str) -> OpfMetadata {
+	let mut reader = Reader::from_str(xml);
+
+	let mut calibre_series = None;
+	let mut calibre_index = None;
+	let mut collections: Vec<(String, String)> = vec![];
+	let mut refinements: HashMap<(String, String), String> = HashMap::new();
+	let mut current_meta: Option<PropertyMeta> = None;
+	let mut identifiers = vec![];
+	let mut current_identifier: Option<Option<String>> = None;
+	let mut dates: Vec<(Option<String>, String)> = vec![]; //with any opf:event
+	let mut current_date: Option<Option<String>> = None;
+	let mut modified = None;
+	let mut people: Vec<Person> = vec![];
+	let mut current_person: Option<Person> = None;
+
+	loop {
+		match reader.read_event() {
+			Ok(Event::Empty(e)) if e.local_name().as_ref() == b"meta" => {
+				apply_calibre_meta(&e, &mut calibre_series, &mut calibre_index);
+			}
+			Ok(Event::Start(e)) if e.local_name().as_ref() == b"meta" => {
+				apply_calibre_meta(&e, &mut calibre_series, &mut calibre_index);
+				current_meta = attr(&e, "property").map(|property| PropertyMeta {
+					id: attr(&e, "id"),
+					property,
+					refines: attr(&e, "refines").map(|refines| refines.trim_start_matches('#').to_string()),
+				});
+			}
+			Ok(Event::Start(e)) if e.local_name().as_ref() == b"identifier" => current_identifier = Some(attr(&e, "scheme")),
+			Ok(Event::Start(e)) if e.local_name().as_ref() == b"creator" || e.local_name().as_ref() == b"contributor" => {
+				current_person = Some(Person {
+					creator: e.local_name().as_ref() == b"creator",
+					id: attr(&e, "id"),
+					role: attr(&e, "role").map(|role| role.to_ascii_lowercase()),
+					name: String::new(),
+				})
+			}
+			Ok(Event::Start(e)) if e.local_name().as_ref() == b"date" => {
+				current_date = Some(attr(&e, "event").map(|event| event.to_ascii_lowercase()))
+			}
+			Ok(Event::Text(t)) => {
+				if let Some(event) = current_date.take() {
+					if let Ok(text) = t.unescape() {
+						dates.push((event, text.trim().to_string()));
+					}
+					continue;
+				}
+				if let Some(mut person) = current_person.take() {
+					if let Ok(text) = t.unescape() {
+						person.name = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+						if !person.name.is_empty() {
+							people.push(person);
+						}
+					}
+					continue;
+				}
+				if let Some(scheme) = current_identifier.take() {
+					if let Ok(text) = t.unescape() {
+						identifiers.push((scheme, text.trim().to_string()));
+					}
+					continue;
+				}
+				let meta = match &current_meta {
+					Some(meta) => meta,
+					None => continue,
+				};
+				let text = match t.unescape() {
+					Ok(text) => text.trim().to_string(),
+					Err(_) => continue,
+				};
+				if text.is_empty() {
+					continue;
+				}
+				match (&meta.refines, meta.property.as_str()) {
+					(None, "belongs-to-collection") => collections.push((meta.id.clone().unwrap_or_default(), text)),
+					(None, "dcterms:modified") => modified = Some(text),
+					//a person can have several roles, the first is the one they're best known for
+					(Some(refines), property) => {
+						refinements.entry((refines.clone(), property.to_string())).or_insert(text);
+					}
+					_ => (),
+				}
+			}
+			Ok(Event::End(e)) if e.local_name().as_ref() == b"meta" => current_meta = None,
+			Ok(Event::End(e)) if e.local_name().as_ref() == b"identifier" => current_identifier = None,
+			Ok(Event::End(e)) if e.local_name().as_ref() == b"date" => current_date = None,
+			Ok(Event::End(e)) if e.local_name().as_ref() == b"creator" || e.local_name().as_ref() == b"contributor" => {
+				current_person = None
+			}
+			Ok(Event::Eof) => break,
+			Err(e) => {
+				eprintln!("Error reading OPF:{}", e);
+				break;
+			}
+			_ => (),
+		}
+	}
+
+	let refinement = |id: &str, property: &str| refinements.get(&(id.to_string(), property.to_string()));
+
+	//dc:creator with no role at all is an author, as is anyone explicitly given that role
+	let mut authors = vec![];
+	let mut contributors = vec![];
+	for person in people {
+		let role = person.role.or_else(|| {
+			person
+				.id
+				.as_ref()
+				.and_then(|id| refinement(id, "role"))
+				.map(|role| role.to_ascii_lowercase())
+		});
+		match role.as_deref() {
+			Some(BOOK_PRODUCER) => (),
+			Some(AUTHOR) => authors.push(person.name),
+			None if person.creator => authors.push(person.name),
+			_ => contributors.push(Contributor { name: person.name, role }),
+		}
+	}
+
+	//calibre's own tags are the most widely written; failing those prefer a collection explicitly typed as a series
+	//OPF2 books can date several events, when it was first published being the one people mean
+	let dated = |event: Option<&str>| dates.iter().find(|(e, _)| e.as_deref() == event).map(|(_, date)| date.clone());
+	let pubdate = dated(Some("original-publication"))
+		.or_else(|| dated(Some("publication")))
+		.or_else(|| dated(None))
+		.or_else(|| {
+			dates
+				.iter()
+				.find(|(event, _)| event.as_deref() != Some("modification"))
+				.map(|(_, date)| date.clone())
+		});
+	let moddate = modified.or_else(|| dated(Some("modification")));
+
+	let mut opf = OpfMetadata {
+		identifiers,
+		pubdate,
+		moddate,
+		authors,
+		contributors,
+		..Default::default()
+	};
+	if calibre_series.is_some() {
+		opf.series = calibre_series;
+		opf.series_index = calibre_index;
+	} else if let Some((id, name)) = collections
+		.iter()
+		.find(|(id, _)| refinement(id, "collection-type").map(|t| t == "series").unwrap_or(false))
+		.or_else(|| collections.iter().find(|(id, _)| refinement(id, "collection-type").is_none()))
+	{
+		opf.series = Some(name.clone());
+		opf.series_index = refinement(id, "group-position").and_then(|position| position.parse::<f64>().ok());
+	}
+
+	opf
+}
+
+//OPF2 style <meta name="calibre:series" content="..."/>
+fn apply_calibre_meta(e: &BytesStart, calibre_series: &mut Option<String>, calibre_index: &mut Option<f64>) {
+	match attr(e, "name").as_deref() {
+		Some("calibre:series") => *calibre_series = attr(e, "content"),
+		Some("calibre:series_index") => *calibre_index = attr(e, "content").and_then(|index| index.parse::<f64>().ok()),
+		_ => (),
+	}
+}
+
+fn attr(e: &BytesStart, name: &str) -> Option<String> {
+	e.attributes()
+		.flatten()
+		.find(|attr| attr.key.local_name().as_ref() == name.as_bytes())
+		.and_then(|attr| attr.unescape_value().ok())
+		.map(|val| val.trim().to_string())
+		.filter(|val| !val.is_empty())
+}
+
+#[test]
+fn test_opf_series() {
+	let calibre = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+		<meta name="calibre:series" content="Foundation"/>
+		<meta name="calibre:series_index" content="2.0"/>
+		<dc:identifier opf:scheme="ISBN">0-553-29336-0</dc:identifier>
+		<dc:creator opf:role="aut" opf:file-as="Asimov, Isaac">Isaac Asimov</dc:creator>
+		<dc:contributor opf:role="bkp">calibre (5.0.1) [https://calibre-ebook.com]</dc:contributor>
+		<dc:date opf:event="modification">2021-03-01</dc:date>
+		<dc:date opf:event="original-publication">1951</dc:date>
+		<dc:date opf:event="publication">1991-10-01</dc:date>
+		<dc:identifier id="uuid_id">urn:uuid:3b8a3e8a-1c2d-4e5f-8a9b-0c1d2e3f4a5b</dc:identifier>
+	</metadata></package>"#;
+	let opf = parse_opf(calibre);
+	assert_eq!(Some("Foundation".to_string()), opf.series);
+	assert_eq!(Some(2.0), opf.series_index);
+	assert_eq!(
+		vec![
+			(Some("ISBN".to_string()), "0-553-29336-0".to_string()),
+			(None, "urn:uuid:3b8a3e8a-1c2d-4e5f-8a9b-0c1d2e3f4a5b".to_string())
+		],
+		opf.identifiers
+	);
+	assert_eq!(vec!["Isaac Asimov".to_string()], opf.authors);
+	assert_eq!(Some("1951".to_string()), opf.pubdate);
+	assert_eq!(Some("2021-03-01".to_string()), opf.moddate);
+	assert!(opf.contributors.is_empty());
+
+	let epub3 = r##"<package xmlns="http://www.idpf.org/2007/opf" version="3.0"><metadata>
+		<meta property="belongs-to-collection" id="c01">Penguin Classics</meta>
+		<meta refines="#c01" property="collection-type">set</meta>
+		<meta property="belongs-to-collection" id="c02">The Barsetshire Novels</meta>
+		<meta refines="#c02" property="collection-type">series</meta>
+		<meta refines="#c02" property="group-position">1.5</meta>
+		<dc:date>1855-01-01</dc:date>
+		<meta property="dcterms:modified">2019-01-15T04:52:30Z</meta>
+		<dc:creator id="author">Anthony Trollope</dc:creator>
+		<dc:creator id="editor">Ruth  Rendell</dc:creator>
+		<meta property="role" refines="#editor" scheme="marc:relators">edt</meta>
+		<meta property="role" refines="#editor" scheme="marc:relators">aui</meta>
+		<dc:contributor id="artist">John Everett Millais</dc:contributor>
+		<meta property="role" refines="#artist" scheme="marc:relators">ill</meta>
+	</metadata></package>"##;
+	let opf = parse_opf(epub3);
+	assert_eq!(Some("The Barsetshire Novels".to_string()), opf.series);
+	assert_eq!(Some(1.5), opf.series_index);
+	assert_eq!(vec!["Anthony Trollope".to_string()], opf.authors);
+	assert_eq!(Some("1855-01-01".to_string()), opf.pubdate);
+	assert_eq!(Some("2019-01-15T04:52:30Z".to_string()), opf.moddate);
+	assert_eq!(
+		vec![
+			Contributor {
+				name: "Ruth Rendell".to_string(),
+				role: Some("edt".to_string())
+			},
+			Contributor {
+				name: "John Everett Millais".to_string(),
+				role: Some("ill".to_string())
+			}
+		],
+		opf.contributors
+	);
+
+	assert!(parse_opf("<package><metadata/></package>").series.is_none());
+}
@@ -0,0 +1,249 @@
+use std::error::Error;
+
+use lopdf::{Dictionary, Document};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+use crate::date::parse_date;
+use crate::BookMetadata;
+
+pub const PDF_MIME: &str = "application/pdf";
+
+//PDF metadata lives in the trailer's Info dictionary and, in anything written this century, an XMP packet hung off the catalog.
+//Where both are present XMP wins as it is the one tools actually keep up to date. Only XMP can say when a book was published -
+//the Info dictionary's CreationDate, like xmp:CreateDate, is just when the file was made, usually years later.
+pub fn parse_pdf(book_loc: &str) -> Result<BookMetadata, Box<dyn Error>> {
+	let doc = Document::load(book_loc)?;
+
+	let mut bm = BookMetadata {
+		mime: PDF_MIME.to_string(),
+		pages: Some(doc.get_pages().len() as i64),
+		..Default::default()
+	};
+
+	if let Ok(info) = doc.trailer.get_deref(b"Info", &doc).and_then(|info| info.as_dict()) {
+		bm.title = get_info_str(info, b"Title", &doc);
+		bm.creator = get_info_str(info, b"Author", &doc);
+		bm.description = get_info_str(info, b"Subject", &doc);
+		bm.subject = get_info_str(info, b"Keywords", &doc).map(|keywords| vec![keywords]);
+		bm.moddate = get_info_str(info, b"ModDate", &doc)
+			.and_then(|date| pdf_date_to_iso(&date))
+			.and_then(|date| parse_date(&date));
+	}
+
+	if let Some(xmp) = get_xmp(&doc) {
+		apply_xmp(&xmp, &mut bm);
+	}
+
+	Ok(bm)
+}
+
+fn get_info_str(info: &Dictionary, key: &[u8], doc: &Document) -> Option<String> {
+	let val = decode_pdf_str(info.get_deref(key, doc).ok()?.as_str().ok()?);
+	if val.is_empty() {
+		None
+	} else {
+		Some(val)
+	}
+}
+
+//Text strings are either UTF-16BE with a BOM, UTF-8 with a BOM (PDF 2.0), or PDFDocEncoding which is near enough Latin-1
+fn decode_pdf_str(bytes: &[u8]) -> String {
+	let decoded = if bytes.starts_with(&[0xFE, 0xFF]) {
+		let utf16: Vec<u16> = bytes[2..].chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+		String::from_utf16_lossy(&utf16)
+	} else if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+		String::from_utf8_lossy(&bytes[3..]).to_string()
+	} else {
+		bytes.iter().map(|b| *b as char).collect()
+	};
+	decoded.trim_matches(|c: char| c.is_whitespace() || c == '\0').to_string()
+}
+
+//PDF dates look like D:YYYYMMDDHHmmSSOHH'mm' where everything after the year is optional
+fn pdf_date_to_iso(date: &str) -> Option<String> {
+	let date = date.trim().trim_start_matches("D:");
+	let digits: String = date.chars().take_while(|c| c.is_ascii_digit()).collect();
+	if digits.len() < 4 {
+		return None;
+	}
+
+	let part = |start: usize, default: &'static str| digits.get(start..start + 2).unwrap_or(default).to_string();
+	let year = &digits[0..4];
+	if digits.len() < 8 {
+		return Some(if digits.len() >= 6 { format!("{}-{}", year, part(4, "01")) } else { year.to_string() });
+	}
+
+	let tz = match &date[digits.len()..] {
+		tz if tz.starts_with('+') || tz.starts_with('-') => {
+			let tz_digits: String = tz.chars().filter(|c| c.is_ascii_digit()).collect();
+			format!(
+				"{}{}:{}",
+				&tz[0..1],
+				tz_digits.get(0..2).unwrap_or("00"),
+				tz_digits.get(2..4).unwrap_or("00")
+			)
+		}
+		_ => "Z".to_string(),
+	};
+
+	Some(format!(
+		"{}-{}-{}T{}:{}:{}{}",
+		year,
+		part(4, "01"),
+		part(6, "01"),
+		part(8, "00"),
+		part(10, "00"),
+		part(12, "00"),
+		tz
+	))
+}
+
+#[test]
+fn test_pdf_dates() {
+	assert_eq!(Some("2019-01-15T04:52:30Z".to_string()), pdf_date_to_iso("D:20190115045230Z"));
+	assert_eq!(Some("2019-01-15T04:52:30+01:00".to_string()), pdf_date_to_iso("D:20190115045230+01'00'"));
+	assert_eq!(Some("2019-01-15T04:52:30-05:30".to_string()), pdf_date_to_iso("D:20190115045230-05'30"));
+	assert_eq!(Some("2019-01-15T00:00:00Z".to_string()), pdf_date_to_iso("D:20190115"));
+	assert_eq!(Some("2019-03".to_string()), pdf_date_to_iso("D:201903"));
+	assert_eq!(Some("1887".to_string()), pdf_date_to_iso("1887"));
+	assert_eq!(None, pdf_date_to_iso("D:"));
+}
+
+fn get_xmp(doc: &Document) -> Option<String> {
+	let stream = doc.catalog().ok()?.get_deref(b"Metadata", doc).ok()?.as_stream().ok()?;
+	let content = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+	Some(String::from_utf8_lossy(&content).to_string())
+}
+
+//Pull the Dublin Core, PRISM and XMP basic properties out of an XMP packet. Properties can be written either as elements or as
+//attributes on rdf:Description, and the dc ones wrap their values in an rdf:Alt, rdf:Seq or rdf:Bag of rdf:li.
+fn apply_xmp(xmp: &str, bm: &mut BookMetadata) {
+	let mut reader = Reader::from_str(xmp);
+	reader.config_mut().trim_text(true);
+
+	let mut path: Vec<String> = vec![];
+	let mut title = None;
+	let mut description = None;
+	let mut creators = vec![];
+	let mut subjects = vec![];
+	let mut date = None;
+
+	loop {
+		match reader.read_event() {
+			Ok(Event::Start(e)) => {
+				apply_xmp_attributes(&e, bm);
+				path.push(String::from_utf8_lossy(e.name().as_ref()).to_string());
+			}
+			Ok(Event::Empty(e)) => apply_xmp_attributes(&e, bm),
+			Ok(Event::End(_)) => {
+				path.pop();
+			}
+			Ok(Event::Text(t)) => {
+				let text = match t.unescape() {
+					Ok(text) => text.trim().to_string(),
+					Err(_) => continue,
+				};
+				if text.is_empty() {
+					continue;
+				}
+				//the property is the nearest ancestor outside the rdf container
+				let property = path.iter().rev().find(|el| !el.starts_with("rdf:")).map(|el| el.as_str());
+				match property {
+					//title and description can come in several languages, the first is the default
+					Some("dc:title") if title.is_none() => title = Some(text),
+					Some("dc:creator") => creators.push(text),
+					Some("dc:description") if description.is_none() => description = Some(text),
+					Some("dc:subject") => subjects.push(text),
+					Some("pdf:Keywords") if subjects.is_empty() => subjects.push(text),
+					Some("prism:publicationDate") => bm.pubdate = parse_date(&text),
+					//a sequence of dates in the order things happened to the book, so the first is as near publication as there is
+					Some("dc:date") if date.is_none() => date = parse_date(&text),
+					Some("xmp:ModifyDate") => bm.moddate = parse_date(&text),
+					_ => (),
+				}
+			}
+			Ok(Event::Eof) => break,
+			Err(e) => {
+				eprintln!("Error reading XMP metadata:{}", e);
+				break;
+			}
+			_ => (),
+		}
+	}
+
+	if title.is_some() {
+		bm.title = title;
+	}
+	if description.is_some() {
+		bm.description = description;
+	}
+	if !creators.is_empty() {
+		bm.creator = creators.first().cloned();
+		bm.creators = Some(creators);
+	}
+	if !subjects.is_empty() {
+		bm.subject = Some(subjects);
+	}
+	if bm.pubdate.is_none() {
+		bm.pubdate = date;
+	}
+}
+
+fn apply_xmp_attributes(e: &quick_xml::events::BytesStart, bm: &mut BookMetadata) {
+	for attr in e.attributes().flatten() {
+		let val = match attr.unescape_value() {
+			Ok(val) => val.trim().to_string(),
+			Err(_) => continue,
+		};
+		if val.is_empty() {
+			continue;
+		}
+		match attr.key.as_ref() {
+			b"prism:publicationDate" => bm.pubdate = parse_date(&val),
+			b"xmp:ModifyDate" => bm.moddate = parse_date(&val),
+			b"pdf:Keywords" if bm.subject.is_none() => bm.subject = Some(vec![val]),
+			_ => (),
+		}
+	}
+}
+
+#[test]
+fn test_xmp() {
+	let xmp = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+  <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+    <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreateDate="2009-02-12T10:00:00Z">
+      <dc:date><rdf:Seq><rdf:li>1859-11-24</rdf:li><rdf:li>1872-01-01</rdf:li></rdf:Seq></dc:date>
+      <dc:title><rdf:Alt><rdf:li xml:lang="x-default">On the Origin of Species</rdf:li></rdf:Alt></dc:title>
+      <dc:creator><rdf:Seq><rdf:li>Charles Darwin</rdf:li><rdf:li>Someone Else</rdf:li></rdf:Seq></dc:creator>
+      <dc:subject><rdf:Bag><rdf:li>evolution</rdf:li><rdf:li>natural selection</rdf:li></rdf:Bag></dc:subject>
+    </rdf:Description>
+  </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+	let mut bm = BookMetadata {
+		title: Some("Untitled".to_string()),
+		..Default::default()
+	};
+	apply_xmp(xmp, &mut bm);
+
+	assert_eq!(Some("On the Origin of Species".to_string()), bm.title);
+	assert_eq!(Some("Charles Darwin".to_string()), bm.creator);
+	assert_eq!(Some(vec!["Charles Darwin".to_string(), "Someone Else".to_string()]), bm.creators);
+	assert_eq!(Some(vec!["evolution".to_string(), "natural selection".to_string()]), bm.subject);
+	assert_eq!(Some(time::macros::datetime!(1859-11-24 0:00 UTC)), bm.pubdate);
+
+	//PRISM's publication date is the more exact, when there is one
+	let prism = xmp.replace(r#"xmp:CreateDate="#, r#"xmlns:prism="http://prismstandard.org/namespaces/basic/2.0/" prism:publicationDate="1860-01-07" xmp:CreateDate="#);
+	let mut bm = BookMetadata::default();
+	apply_xmp(&prism, &mut bm);
+	assert_eq!(Some(time::macros::datetime!(1860-01-07 0:00 UTC)), bm.pubdate);
+
+	//and the file's own creation date is no publication date at all
+	let created = xmp.replace("<rdf:li>1859-11-24</rdf:li><rdf:li>1872-01-01</rdf:li>", "");
+	let mut bm = BookMetadata::default();
+	apply_xmp(&created, &mut bm);
+	assert_eq!(None, bm.pubdate);
+}
@@ -1,17 +1,32 @@
 use epub::doc::EpubDoc;
 use itertools::Itertools;
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecursiveMode, Watcher};
 use rayon::prelude::*;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::fs::File;
 
-use crate::sqlite::Sqlite;
+use crate::comic;
+use crate::date::parse_date;
+use crate::fb2;
+use crate::fulltext;
+use crate::identifier;
+use crate::language::normalise_language;
+use crate::mobi;
+use crate::opf;
+use crate::pdf;
+use crate::sqlite::{IdRegistry, Sqlite};
 use crate::BookWriter;
-use crate::{AuthorCount, BookMetadata, PublisherCount, TagCount};
+use crate::IndexedFile;
+use crate::{AuthorCount, BookMetadata, LanguageCount, ParsedBook, PublisherCount, SeriesCount, TagCount};
+use sha2::{Digest, Sha256};
+use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{mpsc, Mutex, RwLock};
 use std::time::SystemTime;
 use time::format_description::BorrowedFormatItem;
 use time::macros::format_description;
@@ -22,126 +37,320 @@ fn is_hidden(entry: &DirEntry) -> bool {
 	entry.file_name().to_str().map(|s| s.starts_with(".")).unwrap_or(false)
 }
 
-//TODO move these params to struct & pass struct instead
+fn is_book(path: &Path) -> bool {
+	BookFormat::from_path(path).is_some()
+}
+
+//The kinds of book file we know how to index
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BookFormat {
+	Epub,
+	Pdf,
+	Cbz,
+	Cbr,
+	Cb7,
+	Mobi,
+	Azw3,
+	Fb2,
+	Fb2Zip,
+}
+
+pub const BOOK_FORMATS: [BookFormat; 9] = [
+	BookFormat::Epub,
+	BookFormat::Pdf,
+	BookFormat::Cbz,
+	BookFormat::Cbr,
+	BookFormat::Cb7,
+	BookFormat::Mobi,
+	BookFormat::Azw3,
+	BookFormat::Fb2,
+	BookFormat::Fb2Zip,
+];
+
+impl BookFormat {
+	pub fn from_path(path: &Path) -> Option<BookFormat> {
+		let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+		if name.ends_with(".epub") {
+			Some(BookFormat::Epub)
+		} else if name.ends_with(".pdf") {
+			Some(BookFormat::Pdf)
+		} else if name.ends_with(".cbz") {
+			Some(BookFormat::Cbz)
+		} else if name.ends_with(".cbr") {
+			Some(BookFormat::Cbr)
+		} else if name.ends_with(".cb7") {
+			Some(BookFormat::Cb7)
+		} else if name.ends_with(".mobi") {
+			Some(BookFormat::Mobi)
+		} else if name.ends_with(".azw3") {
+			Some(BookFormat::Azw3)
+		} else if name.ends_with(".fb2") {
+			Some(BookFormat::Fb2)
+		} else if name.ends_with(".fb2.zip") {
+			Some(BookFormat::Fb2Zip)
+		} else {
+			None
+		}
+	}
+
+	pub fn from_mime(mime: &str) -> Option<BookFormat> {
+		BOOK_FORMATS.into_iter().find(|format| format.mime() == mime)
+	}
+
+	//eg "epub", or "fb2.zip"
+	pub fn from_extension(extension: &str) -> Option<BookFormat> {
+		let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+		BOOK_FORMATS.into_iter().find(|format| format.extension() == extension)
+	}
+
+	pub fn mime(&self) -> &'static str {
+		match self {
+			BookFormat::Epub => "application/epub+zip",
+			BookFormat::Pdf => pdf::PDF_MIME,
+			BookFormat::Cbz => comic::CBZ_MIME,
+			BookFormat::Cbr => comic::CBR_MIME,
+			BookFormat::Cb7 => comic::CB7_MIME,
+			BookFormat::Mobi => mobi::MOBI_MIME,
+			BookFormat::Azw3 => mobi::AZW3_MIME,
+			BookFormat::Fb2 => fb2::FB2_MIME,
+			BookFormat::Fb2Zip => fb2::FB2_ZIP_MIME,
+		}
+	}
+
+	pub fn extension(&self) -> &'static str {
+		match self {
+			BookFormat::Epub => "epub",
+			BookFormat::Pdf => "pdf",
+			BookFormat::Cbz => "cbz",
+			BookFormat::Cbr => "cbr",
+			BookFormat::Cb7 => "cb7",
+			BookFormat::Mobi => "mobi",
+			BookFormat::Azw3 => "azw3",
+			BookFormat::Fb2 => "fb2",
+			BookFormat::Fb2Zip => "fb2.zip",
+		}
+	}
+}
+
+//Running tag, author, publisher, series and language counts for books added to and removed from the index
+#[derive(Default)]
+struct Bookkeeping {
+	tags: HashMap<String, u32>,
+	creators: HashMap<String, u32>,
+	publishers: HashMap<String, u32>,
+	series: HashMap<String, u32>,
+	languages: HashMap<String, u32>,
+	removed_tags: HashMap<String, u32>,
+	removed_creators: HashMap<String, u32>,
+	removed_publishers: HashMap<String, u32>,
+	removed_series: HashMap<String, u32>,
+	removed_languages: HashMap<String, u32>,
+}
+
+impl Bookkeeping {
+	fn add(&mut self, bm: &BookMetadata) {
+		bm.add_tags(&mut self.tags);
+		//co-written books count towards each of their authors
+		for creator in bm.creators.iter().flatten() {
+			BookMetadata::add_counts(&Some(creator.clone()), &mut self.creators);
+		}
+		BookMetadata::add_counts(&bm.publisher, &mut self.publishers);
+		BookMetadata::add_counts(&bm.series, &mut self.series);
+		BookMetadata::add_counts(&bm.language, &mut self.languages);
+	}
+
+	//bm is as read back from the index
+	fn remove(&mut self, bm: &BookMetadata) {
+		//stored tags have already been split (any nested facet path comes back \0 separated), so count them as they are rather than via add_tags
+		for tag in bm.subject.iter().flatten() {
+			BookMetadata::add_counts(&Some(tag.replace('\u{0}', "/")), &mut self.removed_tags);
+		}
+		for creator in bm.creators.iter().flatten().filter(|c| !c.is_empty()) {
+			BookMetadata::add_counts(&Some(creator.clone()), &mut self.removed_creators);
+		}
+		BookMetadata::add_counts(&bm.publisher.clone().filter(|p| !p.is_empty()), &mut self.removed_publishers);
+		BookMetadata::add_counts(&bm.series, &mut self.removed_series);
+		BookMetadata::add_counts(&bm.language, &mut self.removed_languages);
+	}
+
+	fn write(self, sqlite_writer: &Sqlite, incremental: bool) -> Result<(), rusqlite::Error> {
+		println!(
+			"Writing counts to sqlite - {} creators, {} publishers, {} tags, {} series, {} languages",
+			self.creators.len(),
+			self.publishers.len(),
+			self.tags.len(),
+			self.series.len(),
+			self.languages.len()
+		);
+		sqlite_writer.make_db()?;
+		if incremental {
+			sqlite_writer.update_counts::<AuthorCount>(self.creators, self.removed_creators)?;
+			sqlite_writer.update_counts::<PublisherCount>(self.publishers, self.removed_publishers)?;
+			sqlite_writer.update_counts::<TagCount>(self.tags, self.removed_tags)?;
+			sqlite_writer.update_counts::<SeriesCount>(self.series, self.removed_series)?;
+			sqlite_writer.update_counts::<LanguageCount>(self.languages, self.removed_languages)?;
+		} else {
+			sqlite_writer.write_counts::<AuthorCount>(self.creators)?;
+			sqlite_writer.write_counts::<PublisherCount>(self.publishers)?;
+			sqlite_writer.write_counts::<TagCount>(self.tags)?;
+			sqlite_writer.write_counts::<SeriesCount>(self.series)?;
+			sqlite_writer.write_counts::<LanguageCount>(self.languages)?;
+		}
+		Ok(())
+	}
+}
+
+//How books are to be scanned, shared by the initial scan and the watcher
+pub struct ScanOptions {
+	pub coverdir: String,
+	pub use_coverdir: bool,
+	//update an existing index rather than building a new one
+	pub incremental: bool,
+	//index the text of each book as well as its metadata
+	pub fulltext: bool,
+}
+
+impl ScanOptions {
+	//Books parsed before they are written to the index. With --fulltext every book in a batch has its text in memory
+	//until then, so batches are much smaller.
+	fn batch_size(&self) -> usize {
+		if self.fulltext {
+			100
+		} else {
+			10000
+		}
+	}
+}
+
 //TODO Could this be more intelligently parallelised across different devices?
 // Scanning is IO bound unless on an nvme ssd or something. Therefore if dir A is on /dev/sdb and dir B is on /mnt/synology,
 // scanning across both devices in parrallel can better utilise CPU and get speed ups?
 pub fn scan_dirs(
-	dirs: Vec<String>,
-	coverdir: String,
-	use_coverdir: bool,
-	mut writer: Box<dyn BookWriter + Send + Sync>,
-	sqlite_writer: Sqlite,
+	dirs: &Vec<String>,
+	options: &ScanOptions,
+	writer: &mut dyn BookWriter,
+	sqlite_writer: &Sqlite,
 ) -> Result<(), Box<dyn std::error::Error>> {
-	for directory in &dirs {
+	for directory in dirs {
 		if !Path::new(&directory).exists() {
 			eprintln!("Directory {} does not exist.", &directory);
 			process::exit(3);
 		}
 	}
 
-	let mut total_books: u64 = 0;
-
-	for dir in &dirs {
+	//when updating, anything already indexed whose size and mtime are unchanged is left alone
+	let incremental = options.incremental;
+	let indexed = if incremental { writer.indexed_files()? } else { HashMap::new() };
+	sqlite_writer.make_db()?;
+	let mut registry = sqlite_writer.load_registry()?;
+	registry.adopt(&indexed);
+	let mut on_disk = HashSet::new();
+	let mut stale_ids = vec![]; //of books no longer there
+	let mut changed = HashMap::new(); //the id indexed from each changed file, until it has been read again
+	let mut unchanged: u64 = 0;
+	let mut book_paths = vec![];
+
+	for dir in dirs {
 		for entry in WalkDir::new(&dir).into_iter().filter_entry(|e| !is_hidden(e)) {
 			match entry {
 				Ok(l) => {
-					if l.file_type().is_file() && l.path().display().to_string().ends_with(".epub") {
-						total_books += 1;
+					if l.file_type().is_file() && is_book(l.path()) {
+						if incremental {
+							if let Ok(file) = l.path().canonicalize() {
+								let file = file.display().to_string();
+								if let Some(indexed_file) = indexed.get(&file) {
+									if l.metadata().map(|md| indexed_file.is_unchanged(&md)).unwrap_or(false) {
+										unchanged += 1;
+										on_disk.insert(file);
+										continue;
+									}
+									changed.insert(file.clone(), indexed_file.id);
+								}
+								on_disk.insert(file);
+							}
+						}
+						book_paths.push(l.path().display().to_string());
 					}
 				}
-				Err(_) => process::exit(1),
+				Err(e) => {
+					eprintln!("Unrecoverable error while scanning books:{}", e);
+					process::exit(1);
+				}
 			}
 		}
 	}
 
+	if incremental {
+		for (file, indexed_file) in &indexed {
+			if !on_disk.contains(file) {
+				stale_ids.push(indexed_file.id);
+			}
+		}
+		println!(
+			"{} books already indexed - {} unchanged, {} changed, {} removed.",
+			indexed.len(),
+			unchanged,
+			changed.len(),
+			stale_ids.len()
+		);
+	}
+
+	let total_books = book_paths.len() as u64;
 	println!("{} books to be scanned.", &total_books);
 
-	//TODO make this a bookkeeping struct
-	let mut tags = HashMap::new();
-	let mut creator_counts = HashMap::new();
-	let mut publisher_counts = HashMap::new();
-	let seen_bookids = std::sync::RwLock::new(HashSet::new());
+	let mut bookkeeping = Bookkeeping::default();
+	let seen_bookids = RwLock::new(HashSet::new());
 	let mut wrote: u64 = 0;
-	let errored = std::sync::Mutex::new(0);
+	let errored = Mutex::new(0);
 	let mut processed: u64 = 0;
 	let mut batch_start = SystemTime::now();
 	let scan_start = SystemTime::now();
-	let mut book_batch = vec![];
 
-	for dir in &dirs {
-		let walker = WalkDir::new(&dir).into_iter();
-		for entry in walker.filter_entry(|e| !is_hidden(e)) {
-			match entry {
-				Ok(l) => {
-					if l.file_type().is_file() && l.path().display().to_string().ends_with(".epub") {
-						book_batch.push(l.path().display().to_string());
-
-						processed += 1;
-
-						if processed % 10000 == 0 || processed >= total_books {
-							let bms: Vec<BookMetadata> = book_batch
-								.par_iter()
-								.map(|book_path| match parse_epub(book_path, use_coverdir, &coverdir) {
-									Ok(bm) => {
-										if !seen_bookids.read().unwrap().contains(&bm.id) {
-											seen_bookids.write().unwrap().insert(bm.id);
-											Some(bm)
-										} else {
-											None
-										}
-									}
-									Err(err) => {
-										eprintln!("Error with {}: {:?}", book_path, err);
-										let mut error_lock = errored.lock().unwrap();
-										*error_lock += 1;
-										None
-									}
-								})
-								.filter(|bmo| bmo.is_some())
-								.map(|bms| bms.unwrap())
-								.collect();
-
-							wrote += bms.len() as u64;
-
-							if let Err(e) = writer.write_epubs(&bms) {
-								eprintln!("Error writing batch:{}", e);
-							} else {
-								for bm in &bms {
-									bm.add_tags(&mut tags);
-									BookMetadata::add_counts(&bm.creator, &mut creator_counts);
-									BookMetadata::add_counts(&bm.publisher, &mut publisher_counts);
-								}
-								writer.commit()?;
-							}
+	for (file, indexed_file) in &indexed {
+		//a changed book may be read again with the same id
+		if !changed.contains_key(file) {
+			seen_bookids.write().unwrap().insert(indexed_file.id);
+		}
+	}
 
-							report_progress(processed, total_books, wrote, batch_start, scan_start);
-							batch_start = SystemTime::now();
+	//books no longer there are removed before anything is parsed, so a moved book can come back with the same id
+	if !stale_ids.is_empty() {
+		for id in remove_books(&stale_ids, &[], options, writer, &mut bookkeeping)? {
+			seen_bookids.write().unwrap().remove(&id);
+		}
+		writer.commit()?;
+	}
 
-							book_batch.clear();
-						}
-					}
-				}
-				Err(e) => {
-					eprintln!("Unrecoverable error while scanning books:{}", e);
-					process::exit(1);
-				}
+	for book_batch in book_paths.chunks(options.batch_size()) {
+		processed += book_batch.len() as u64;
+
+		let bms = parse_books(book_batch, options, &registry, &seen_bookids, &errored);
+
+		wrote += bms.len() as u64;
+
+		//a changed book's old version is only removed once the new one has been read, in the same commit as it is added
+		let replaced: Vec<i64> = bms.iter().filter_map(|bm| changed.remove(&bm.file)).collect();
+		remove_books(&replaced, &bms, options, writer, &mut bookkeeping)?;
+
+		if let Err(e) = writer.write_epubs(&bms) {
+			eprintln!("Error writing batch:{}", e);
+		} else {
+			for bm in &bms {
+				bookkeeping.add(bm);
+				registry.add(bm);
 			}
+			writer.commit()?;
+			sqlite_writer.register_ids(&bms)?;
 		}
+
+		report_progress(processed, total_books, wrote, batch_start, scan_start);
+		batch_start = SystemTime::now();
 	}
 
 	report_final(total_books, wrote, *errored.lock().unwrap(), scan_start);
+	report_kept(&changed);
 
-	println!(
-		"Writing counts to sqlite - {} creators, {} publishers, {} tags",
-		creator_counts.len(),
-		publisher_counts.len(),
-		tags.len()
-	);
-	sqlite_writer.make_db()?;
-	sqlite_writer.write_counts::<AuthorCount>(creator_counts)?;
-	sqlite_writer.write_counts::<PublisherCount>(publisher_counts)?;
-	sqlite_writer.write_counts::<TagCount>(tags)?;
+	bookkeeping.write(sqlite_writer, incremental)?;
 
 	println!("Scan complete.");
 	//we commit only once at the end, this results in one segment which is much faster than 5000 segments
@@ -151,51 +360,263 @@ pub fn scan_dirs(
 	Ok(())
 }
 
-fn parse_epub(book_loc: &str, use_coverdir: bool, coverdir: &str) -> Result<BookMetadata, Box<dyn Error>> {
-	let mut doc = EpubDoc::new(&book_loc)?;
-	let metadata = fs::metadata(&book_loc)?;
-	let modtime = metadata.modified().unwrap_or(std::time::UNIX_EPOCH).into();
+//Keep the index up to date as books are added to, changed in or removed from dirs. Runs until the process is killed.
+pub fn watch_dirs(
+	dirs: &Vec<String>,
+	options: &ScanOptions,
+	writer: &mut dyn BookWriter,
+	sqlite_writer: &Sqlite,
+) -> Result<(), Box<dyn std::error::Error>> {
+	let (tx, rx) = mpsc::channel();
+	let mut watcher = notify::recommended_watcher(tx)?;
+	//watch canonical paths so event paths line up with the canonical paths stored in the index
+	for dir in dirs {
+		watcher.watch(&Path::new(dir).canonicalize()?, RecursiveMode::Recursive)?;
+	}
 
-	let cover_img = if use_coverdir { doc.get_cover() } else { None };
+	let mut indexed = writer.indexed_files()?;
+	sqlite_writer.make_db()?;
+	let mut registry = sqlite_writer.load_registry()?;
+	registry.adopt(&indexed);
+	println!("Watching {} for changes.", dirs.join(", "));
+
+	loop {
+		//block until something happens, then give a download or copy a couple of seconds of quiet before processing the lot
+		let mut changed_paths = HashSet::new();
+		let mut event = rx.recv()?;
+		loop {
+			match event {
+				Ok(event) => match event.kind {
+					EventKind::Create(_)
+					| EventKind::Modify(_)
+					| EventKind::Remove(_)
+					| EventKind::Access(AccessKind::Close(AccessMode::Write)) => changed_paths.extend(event.paths),
+					_ => (),
+				},
+				Err(e) => eprintln!("Error watching for changes:{}", e),
+			}
+			event = match rx.recv_timeout(std::time::Duration::from_secs(2)) {
+				Ok(event) => event,
+				Err(mpsc::RecvTimeoutError::Timeout) => break,
+				Err(e) => return Err(Box::new(e)),
+			};
+		}
 
-	let cover_mime = match doc.get_cover_id() {
-		Some(cover_id) => doc.get_resource_mime(&cover_id),
-		None => None,
+		let (removed, added) = apply_changes(changed_paths, options, writer, sqlite_writer, &mut indexed, &mut registry)?;
+		if removed > 0 || added > 0 {
+			println!("Removed {} books, added {}.", removed, added);
+		}
+	}
+}
+
+//Bring the index and counts up to date with the books at, or under, the paths the watcher saw change. Paths are canonical,
+//and indexed and registry are kept up to date too. Returns how many books were removed and added.
+pub fn apply_changes(
+	changed_paths: HashSet<PathBuf>,
+	options: &ScanOptions,
+	writer: &mut dyn BookWriter,
+	sqlite_writer: &Sqlite,
+	indexed: &mut HashMap<String, IndexedFile>,
+	registry: &mut IdRegistry,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+	let mut stale_ids = vec![]; //of books no longer there
+	let mut changed = HashMap::new(); //the id indexed from each changed file, until it has been read again
+	let mut book_paths = vec![];
+	for path in changed_paths {
+		let file = path.display().to_string();
+		if path.is_dir() {
+			//a directory moved into place arrives as a single event
+			for entry in WalkDir::new(&path).into_iter().filter_entry(|e| !is_hidden(e)).flatten() {
+				if entry.file_type().is_file() && is_book(entry.path()) {
+					changed_book(entry.path().to_path_buf(), indexed, &mut changed, &mut book_paths);
+				}
+			}
+		} else if path.is_file() {
+			if is_book(&path) && !path.file_name().map(|f| f.to_string_lossy().starts_with(".")).unwrap_or(false) {
+				changed_book(path, indexed, &mut changed, &mut book_paths);
+			}
+		} else {
+			//gone - either a single book or a whole directory of them
+			let dir_prefix = format!("{}/", &file);
+			for (indexed_path, indexed_file) in indexed.iter() {
+				if indexed_path == &file || indexed_path.starts_with(&dir_prefix) {
+					stale_ids.push(indexed_file.id);
+				}
+			}
+		}
+	}
+
+	if stale_ids.is_empty() && book_paths.is_empty() {
+		return Ok((0, 0));
+	}
+
+	let mut bookkeeping = Bookkeeping::default();
+	let mut removed = remove_books(&stale_ids, &[], options, writer, &mut bookkeeping)?;
+	indexed.retain(|_, indexed_file| !removed.contains(&indexed_file.id));
+
+	//a changed book may be read again with the same id
+	let seen_bookids = RwLock::new(
+		indexed
+			.iter()
+			.filter(|(file, _)| !changed.contains_key(*file))
+			.map(|(_, indexed_file)| indexed_file.id)
+			.collect(),
+	);
+	let errored = Mutex::new(0);
+	let bms = parse_books(&book_paths, options, registry, &seen_bookids, &errored);
+
+	//and its old version is only removed once the new one has been read
+	let replaced: Vec<i64> = bms.iter().filter_map(|bm| changed.remove(&bm.file)).collect();
+	let replaced = remove_books(&replaced, &bms, options, writer, &mut bookkeeping)?;
+	indexed.retain(|_, indexed_file| !replaced.contains(&indexed_file.id));
+	removed.extend(replaced);
+	report_kept(&changed);
+
+	if let Err(e) = writer.write_epubs(&bms) {
+		eprintln!("Error writing batch:{}", e);
+	} else {
+		sqlite_writer.register_ids(&bms)?;
+		for bm in &bms {
+			bookkeeping.add(bm);
+			registry.add(bm);
+			indexed.insert(
+				bm.file.clone(),
+				IndexedFile {
+					id: bm.id,
+					filesize: bm.filesize,
+					modtime: bm.modtime,
+				},
+			);
+		}
+	}
+	writer.commit()?;
+	bookkeeping.write(sqlite_writer, true)?;
+
+	Ok((removed.len(), bms.len()))
+}
+
+//Queue a book seen by the watcher for parsing, unless the index already has this exact file
+fn changed_book(path: PathBuf, indexed: &HashMap<String, IndexedFile>, changed: &mut HashMap<String, i64>, book_paths: &mut Vec<String>) {
+	let file = path.display().to_string();
+	if let Some(indexed_file) = indexed.get(&file) {
+		if fs::metadata(&path).map(|md| indexed_file.is_unchanged(&md)).unwrap_or(false) {
+			return;
+		}
+		changed.insert(file.clone(), indexed_file.id);
+	}
+	book_paths.push(file);
+}
+
+//Remove books from the index (and their covers, unless a replacement has the same id), returning the ids actually removed
+fn remove_books(
+	ids: &[i64],
+	replacements: &[BookMetadata],
+	options: &ScanOptions,
+	writer: &mut dyn BookWriter,
+	bookkeeping: &mut Bookkeeping,
+) -> Result<Vec<i64>, Box<dyn Error>> {
+	let mut removed = vec![];
+	if ids.is_empty() {
+		return Ok(removed);
+	}
+	for bm in writer.remove_epubs(ids)? {
+		bookkeeping.remove(&bm);
+		if options.use_coverdir && !replacements.iter().any(|replacement| replacement.id == bm.id) {
+			fs::remove_file(format!("{}/{}", options.coverdir, &bm.id)).ok();
+		}
+		removed.push(bm.id);
+	}
+	Ok(removed)
+}
+
+//Changed books that couldn't be read again keep what was indexed from them before, but say so
+fn report_kept(changed: &HashMap<String, i64>) {
+	for file in changed.keys() {
+		eprintln!("{} changed but was not indexed again, so what was indexed from it before is kept.", file);
+	}
+}
+
+//Parse a batch of books in parallel, skipping any whose id has already been seen
+fn parse_books(
+	book_paths: &[String],
+	options: &ScanOptions,
+	registry: &IdRegistry,
+	seen_bookids: &RwLock<HashSet<i64>>,
+	errored: &Mutex<u64>,
+) -> Vec<BookMetadata> {
+	book_paths
+		.par_iter()
+		.map(|book_path| match parse_book(book_path, options, registry) {
+			Ok(bm) => {
+				if !seen_bookids.read().unwrap().contains(&bm.id) {
+					seen_bookids.write().unwrap().insert(bm.id);
+					Some(bm)
+				} else {
+					None
+				}
+			}
+			Err(err) => {
+				eprintln!("Error with {}: {:?}", book_path, err);
+				let mut error_lock = errored.lock().unwrap();
+				*error_lock += 1;
+				None
+			}
+		})
+		.filter(|bmo| bmo.is_some())
+		.map(|bms| bms.unwrap())
+		.collect()
+}
+
+fn parse_book(book_loc: &str, options: &ScanOptions, registry: &IdRegistry) -> Result<BookMetadata, Box<dyn Error>> {
+	let format = match BookFormat::from_path(Path::new(book_loc)) {
+		Some(format) => format,
+		None => return Err(Box::new(io::Error::other("Not a known book format"))),
+	};
+
+	let (mut bm, cover_img) = match format {
+		BookFormat::Epub => parse_epub(book_loc, options.use_coverdir, options.fulltext)?,
+		BookFormat::Pdf => (pdf::parse_pdf(book_loc)?, None),
+		BookFormat::Cbz | BookFormat::Cbr | BookFormat::Cb7 => comic::parse_comic(book_loc, format, options.use_coverdir)?,
+		BookFormat::Mobi | BookFormat::Azw3 => mobi::parse_mobi(book_loc, options.use_coverdir)?,
+		BookFormat::Fb2 | BookFormat::Fb2Zip => fb2::parse_fb2(book_loc, format, options.use_coverdir)?,
 	};
 
-	let file = match Path::new(&book_loc).canonicalize() {
+	let metadata = fs::metadata(&book_loc)?;
+	bm.file = match Path::new(&book_loc).canonicalize() {
 		Ok(f) => f.display().to_string(),
 		Err(e) => {
 			eprintln!("Could not canonicalize {}", &e);
 			return Err(Box::new(e));
 		}
 	};
+	bm.filesize = metadata.len() as i64;
+	bm.modtime = metadata.modified().unwrap_or(std::time::UNIX_EPOCH).into();
+	bm.mime = format.mime().to_string();
+	bm.content_hash = Some(hash_file(book_loc)?);
+	if bm.series_index.is_none() {
+		bm.series_index = bm.issue.as_ref().and_then(|issue| issue.parse::<f64>().ok());
+	}
+	normalise_identifiers(&mut bm);
+	bm.language = bm.language.take().and_then(|language| normalise_language(&language));
+	//formats give either every author or just the one, and creator is always the first of them
+	match (&bm.creators, &bm.creator) {
+		(Some(creators), _) => bm.creator = creators.first().cloned(),
+		(None, Some(creator)) => bm.creators = Some(vec![creator.clone()]),
+		(None, None) => (),
+	}
 
-	let mut bm = BookMetadata {
-		id: 0i64,
-		title: get_first_fd("title", &doc.metadata),
-		description: get_first_fd("description", &doc.metadata),
-		publisher: get_first_fd("publisher", &doc.metadata),
-		creator: get_first_fd("creator", &doc.metadata).map(unmangle_creator),
-		subject: doc.metadata.get("subject").cloned(),
-		file,
-		filesize: metadata.len() as i64,
-		modtime,
-		pubdate: get_first_fd("date", &doc.metadata),
-		moddate: get_first_fd("date", &doc.metadata),
-		cover_mime,
-	};
-
-	bm.id = bm.hash_md();
+	bm.id = registry
+		.resolve(bm.content_hash.as_ref().unwrap(), &bm.file)
+		.unwrap_or_else(|| bm.hash_md());
 
-	if use_coverdir {
+	if options.use_coverdir {
 		match cover_img {
 			Some(cover) => {
-				let mut file = File::create(format!("{}/{}", coverdir, &bm.id)).or_else(|e| {
+				let mut file = File::create(format!("{}/{}", options.coverdir, &bm.id)).or_else(|e| {
 					eprintln!("Could not create cover file for {}", &book_loc);
 					Err(e)
 				})?;
-				file.write_all(&cover.0).or_else(|e| {
+				file.write_all(&cover).or_else(|e| {
 					eprintln!("Error writing to cover dir for {}", &book_loc);
 					Err(e)
 				})?;
@@ -207,6 +628,99 @@ fn parse_epub(book_loc: &str, use_coverdir: bool, coverdir: &str) -> Result<Book
 	Ok(bm)
 }
 
+//Formats with just an ISBN field leave it as found, so fold it in with the rest of the identifiers and keep only a valid, normalised ISBN
+fn normalise_identifiers(bm: &mut BookMetadata) {
+	let mut identifiers = bm.identifiers.take().unwrap_or_default();
+	if let Some(isbn) = bm.isbn.as_ref().and_then(|isbn| identifier::classify(isbn, Some(identifier::ISBN))) {
+		if !identifiers.contains(&isbn) {
+			identifiers.push(isbn);
+		}
+	}
+
+	let isbn_prefix = format!("{}:", identifier::ISBN);
+	bm.isbn = identifiers
+		.iter()
+		.find_map(|id| id.strip_prefix(&isbn_prefix))
+		.map(|isbn| isbn.to_string());
+	bm.identifiers = if identifiers.is_empty() { None } else { Some(identifiers) };
+}
+
+//Returns the cover image only if it is wanted for the cover dir
+fn parse_epub(book_loc: &str, use_coverdir: bool, fulltext: bool) -> Result<ParsedBook, Box<dyn Error>> {
+	let mut doc = EpubDoc::new(&book_loc)?;
+
+	let cover_img = if use_coverdir { doc.get_cover().map(|cover| cover.0) } else { None };
+
+	let cover_mime = match doc.get_cover_id() {
+		Some(cover_id) => doc.get_resource_mime(&cover_id),
+		None => None,
+	};
+
+	let opf = match doc.get_resource_str_by_path(doc.root_file.clone()) {
+		Some(xml) => opf::parse_opf(&xml),
+		None => opf::OpfMetadata::default(),
+	};
+	let identifiers: Vec<String> = opf
+		.identifiers
+		.iter()
+		.filter_map(|(scheme, value)| identifier::classify(value, scheme.as_deref()))
+		.unique()
+		.collect();
+	//epub-rs has no idea of roles, so only fall back on its creators when the package document couldn't be read
+	let creators: Vec<String> = if opf.authors.is_empty() && opf.contributors.is_empty() {
+		doc.metadata.get("creator").cloned().unwrap_or_default()
+	} else {
+		opf.authors
+	}
+	.into_iter()
+	.map(unmangle_creator)
+	.unique()
+	.collect();
+
+	let bm = BookMetadata {
+		title: get_first_fd("title", &doc.metadata),
+		description: get_first_fd("description", &doc.metadata),
+		publisher: get_first_fd("publisher", &doc.metadata),
+		creators: if creators.is_empty() { None } else { Some(creators) },
+		contributors: if opf.contributors.is_empty() { None } else { Some(opf.contributors) },
+		subject: doc.metadata.get("subject").cloned(),
+		pubdate: opf.pubdate.or_else(|| get_first_fd("date", &doc.metadata)).and_then(|date| parse_date(&date)),
+		moddate: opf.moddate.and_then(|date| parse_date(&date)),
+		language: get_first_fd("language", &doc.metadata),
+		issue: opf.series_index.map(|index| index.to_string()),
+		series: opf.series,
+		series_index: opf.series_index,
+		identifiers: if identifiers.is_empty() { None } else { Some(identifiers) },
+		cover_mime,
+		content: if fulltext { Some(fulltext::epub_text(&mut doc)) } else { None },
+		..Default::default()
+	};
+
+	Ok((bm, cover_img))
+}
+
+//Pull the cover image and its mime type straight out of a book file
+pub fn extract_cover(file: &str, format: BookFormat) -> Option<(Vec<u8>, String)> {
+	match format {
+		BookFormat::Epub => {
+			let mut epub = EpubDoc::new(file).ok()?;
+			let cover = epub.get_cover()?;
+			let mime = epub.get_resource_mime(&epub.get_cover_id()?)?;
+			Some((cover.0, mime))
+		}
+		BookFormat::Pdf => None,
+		BookFormat::Cbz | BookFormat::Cbr | BookFormat::Cb7 => comic::comic_cover(file, format),
+		BookFormat::Mobi | BookFormat::Azw3 => mobi::mobi_cover(file),
+		BookFormat::Fb2 | BookFormat::Fb2Zip => fb2::fb2_cover(file, format),
+	}
+}
+
+fn hash_file(book_loc: &str) -> Result<String, Box<dyn Error>> {
+	let mut hasher = Sha256::new();
+	io::copy(&mut File::open(book_loc)?, &mut hasher)?;
+	Ok(format!("{:x}", hasher.finalize()))
+}
+
 fn get_first_fd(mdfield: &str, md: &HashMap<String, Vec<String>>) -> Option<String> {
 	match md.get(mdfield) {
 		Some(vec) => Some(vec.get(0).unwrap().clone()),
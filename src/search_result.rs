@@ -10,6 +10,22 @@ pub struct SearchResult<T: Debug + serde::Serialize> {
 	pub start: usize,
 	pub query: Option<String>,
 	pub payload: Vec<T>,
+	pub facets: Option<Vec<FacetGroup>>, //counts within the results, when asked for
+	pub cursor: Option<String>, //to fetch the page after this one, if there is one
+	pub previous_cursor: Option<String>, //and the one before
+}
+
+//eg the tags among the books found, with how many books have each
+#[derive(Debug, Serialize)]
+pub struct FacetGroup {
+	pub facet: String,
+	pub values: Vec<FacetValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FacetValue {
+	pub value: String,
+	pub count: u64,
 }
 
 #[derive(Debug)]
@@ -30,6 +46,28 @@ pub struct OpdsPage {
 	pub date: String,
 	pub title: String,
 	pub url: String,
+	pub kind: &'static str, //navigation or acquisition
+	pub links: Vec<OpdsLink>, //to other pages of the same feed
+	pub total_results: usize,
+	pub items_per_page: usize,
+	pub start_index: usize, //counting from 1, as OpenSearch does
+	pub facets: Vec<OpdsFacet>, //of a feed of books
+}
+
+#[derive(Debug)]
+pub struct OpdsLink {
+	pub rel: String,
+	pub href: String,
+}
+
+//A link that orders or narrows down a feed of books, which readers show as a filter menu
+#[derive(Debug)]
+pub struct OpdsFacet {
+	pub group: String, //eg "Languages"
+	pub title: String,
+	pub href: String,
+	pub count: Option<u64>,
+	pub active: bool,
 }
 
 impl<T: Debug + serde::Serialize> SearchResult<T> {
@@ -59,7 +97,18 @@ impl<T: Debug + serde::Serialize> SearchResult<T> {
 			}
 		}
 
-		json_str.push_str("]}");
+		json_str.push(']');
+		if let Some(cursor) = &self.cursor {
+			json_str.push_str(&format!(", \"cursor\":\"{}\"", cursor));
+		}
+		if let Some(previous_cursor) = &self.previous_cursor {
+			json_str.push_str(&format!(", \"previous_cursor\":\"{}\"", previous_cursor));
+		}
+		if let Some(facets) = &self.facets {
+			json_str.push_str(", \"facets\":");
+			json_str.push_str(&serde_json::to_string(facets).unwrap_or_else(|_| "[]".to_string()));
+		}
+		json_str.push('}');
 		json_str
 	}
 }
@@ -1,30 +1,63 @@
+use crate::identifier;
+use crate::scanner::{extract_cover, BookFormat};
 use crate::sqlite::Sqlite;
-use crate::ttvy::TantivyReader;
-
-use epub::doc::EpubDoc;
+use crate::suggest::{Suggester, Suggestion};
+use crate::ttvy::{Cursor, SearchOptions, Sort, TantivyReader, FACET_KINDS};
 
 use crate::error::ClientError;
 use crate::error::StoreError;
-use rouille::Response;
+use rouille::{Request, Response};
 use std::convert::TryInto;
 use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::sync::RwLock;
 
-use crate::search_result::OpdsPage;
+use crate::opds2::{Feed, FeedMetadata, Group, Link, Publication, OPDS_JSON};
+use crate::search_result::{OpdsFacet, OpdsLink, OpdsPage, SearchResult};
+use crate::BookMetadata;
 use crate::OpdsCategory;
-use crate::{AuthorCount, PublisherCount, TagCount};
+use crate::language::language_name;
+use crate::{AuthorCount, LanguageCount, PublisherCount, SeriesCount, TagCount};
 
 use urlencoding::{decode, encode};
 
 include!(concat!(env!("OUT_DIR"), "/templates.rs"));
 
+//Any more tags than this beginning with the same letters are split up by the letter after
+const MAX_TAGS_LISTED: usize = 100;
+
+//The orders OPDS readers are offered for a feed of books, as the "sort" param
+const SORT_FACETS: [(&str, &str); 3] = [("Newest", "newest"), ("Title", "title"), ("Author", "author")];
+
+//Books in the newest group on the front page of the OPDS 2 catalog
+const NEWEST_LISTED: usize = 10;
+
+//The same OPDS catalog is served as Atom (OPDS 1.2) under /opds and as JSON (OPDS 2.0) under /opds/v2
+#[derive(Clone, Copy, PartialEq)]
+enum OpdsFormat {
+	Atom,
+	Json,
+}
+
+impl OpdsFormat {
+	//eg "/authors" to the authors feed in this format
+	fn url(&self, path: &str) -> String {
+		match self {
+			OpdsFormat::Atom => format!("/opds{}", path),
+			OpdsFormat::Json => format!("/opds/v2{}", path),
+		}
+	}
+}
+
 pub struct Server {
 	pub reader: TantivyReader,
 	pub sqlite: Sqlite,
+	pub suggester: RwLock<Suggester>, //built again when the index changes
 	pub host: String,
 	pub port: u16,
+	pub page_size: usize, //books or navigation entries in each page of an OPDS feed
 	pub use_coverdir: bool,
 	pub coverdir: String,
 }
@@ -43,15 +76,27 @@ impl fmt::Display for ServerError {
 }
 
 impl Server {
-	pub fn new(reader: TantivyReader, sqlite: Sqlite, host: String, port: u16, use_coverdir: bool, coverdir: String) -> Server {
-		Server {
+	//Suggestions are built from the index up front, so this fails if they can't be
+	pub fn new(
+		reader: TantivyReader,
+		sqlite: Sqlite,
+		host: String,
+		port: u16,
+		page_size: usize,
+		use_coverdir: bool,
+		coverdir: String,
+	) -> Result<Server, Box<dyn std::error::Error>> {
+		let suggester = Suggester::build(&sqlite, &reader)?;
+		Ok(Server {
 			reader,
 			sqlite,
+			suggester: RwLock::new(suggester),
 			host,
 			port,
+			page_size: page_size.max(1),
 			use_coverdir,
 			coverdir,
-		}
+		})
 	}
 
 	#[allow(unreachable_code)]
@@ -79,7 +124,47 @@ impl Server {
 							Err(_) => return self.get_json_error_response("Type error", "\"limit\" should have an integer argument"),
 						};
 
-						return match self.reader.search(query_str, start, limit) {
+						let highlight = match request.get_param("highlight").unwrap_or_else(|| "false".to_string()).parse::<bool>() {
+							Ok(highlight) => highlight,
+							Err(_) => return self.get_json_error_response("Type error", "\"highlight\" should be true or false"),
+						};
+
+						let fuzzy = match request.get_param("fuzzy").unwrap_or_else(|| "false".to_string()).parse::<bool>() {
+							Ok(fuzzy) => fuzzy,
+							Err(_) => return self.get_json_error_response("Type error", "\"fuzzy\" should be true or false"),
+						};
+
+						let sort = match self.get_sort(request) {
+							Some(sort) => sort,
+							None => return self.get_json_error_response("Sort error", "\"sort\" should be one of relevance, title, author, modtime, pubdate or filesize, and \"order\" asc or desc"),
+						};
+
+						let facets = match request.get_param("facets").unwrap_or_else(|| "false".to_string()).parse::<bool>() {
+							Ok(facets) => facets,
+							Err(_) => return self.get_json_error_response("Type error", "\"facets\" should be true or false"),
+						};
+
+						//drill down by any of tag, author, publisher, lang and decade
+						let filters = FACET_KINDS.iter().filter_map(|kind| request.get_param(kind.name()).map(|value| (*kind, value))).collect();
+
+						//carries on from a previous page, in which case start is only informational
+						let after = match request.get_param("cursor") {
+							Some(cursor) => match Cursor::parse(&cursor) {
+								Some(cursor) => Some(cursor),
+								None => return self.get_json_error_response("Cursor error", "\"cursor\" should be as returned by a previous search"),
+							},
+							None => None,
+						};
+						//or goes back from one
+						let before = match request.get_param("before") {
+							Some(cursor) => match Cursor::parse(&cursor) {
+								Some(cursor) => Some(cursor),
+								None => return self.get_json_error_response("Cursor error", "\"before\" should be a previous_cursor returned by a previous search"),
+							},
+							None => None,
+						};
+
+						return match self.reader.search(query_str, start, limit, &SearchOptions { highlight, fuzzy, sort, facets, filters, after, before }) {
 							Ok(response) => Response::from_data("application/json", response.to_json()).with_additional_header("Access-Control-Allow-Origin", "*"),
 							Err(e) => {
 								if let StoreError::ClientError(ce) = e {
@@ -150,9 +235,38 @@ impl Server {
 									Err(_) => self.get_json_error_response("Publisher error", "Unable to query publisher counts").with_status_code(500)
 								}
 							},
+							"series" => {
+								match self.sqlite.get_counts::<SeriesCount>(order, asc, start.try_into().unwrap(), limit.try_into().unwrap(), filter ) {
+									Ok(res) => Response::from_data("application/json", res.to_json()).with_additional_header("Access-Control-Allow-Origin", "*"),
+									Err(_) => self.get_json_error_response("Series error", "Unable to query series counts").with_status_code(500)
+								}
+							},
+							"languages" => {
+								match self.sqlite.get_counts::<LanguageCount>(order, asc, start.try_into().unwrap(), limit.try_into().unwrap(), filter ) {
+									Ok(res) => Response::from_data("application/json", res.to_json()).with_additional_header("Access-Control-Allow-Origin", "*"),
+									Err(_) => self.get_json_error_response("Language error", "Unable to query language counts").with_status_code(500)
+								}
+							},
 							_ => Response::empty_404()
 						};
 					},
+					(GET) (/api/suggest) => {
+						let prefix = request.get_param("q").unwrap_or_default();
+
+						let limit = match request.get_param("limit").unwrap_or_else(|| "10".to_string()).parse::<usize>() {
+							Ok(lim) => lim,
+							Err(_) => return self.get_json_error_response("Type error", "\"limit\" should have an integer argument"),
+						};
+
+						let suggestions = self.suggestions(&prefix, limit);
+						//OpenSearch suggestions are just the query and the completions
+						if request.get_param("format").as_deref() == Some("opensearch") {
+							let texts: Vec<&String> = suggestions.payload.iter().map(|suggestion| &suggestion.text).collect();
+							return Response::from_data("application/x-suggestions+json", serde_json::to_string(&(&prefix, texts)).unwrap_or_default())
+								.with_additional_header("Access-Control-Allow-Origin", "*");
+						}
+						Response::from_data("application/json", suggestions.to_json()).with_additional_header("Access-Control-Allow-Origin", "*")
+					},
 					(GET) (/api/opensearch) => {
 						Response::text("<?xml version=\"1.0\" encoding=\"UTF-8\"?>
 						<OpenSearchDescription xmlns=\"http://a9.com/-/spec/opensearch/1.1/\">
@@ -161,20 +275,42 @@ impl Server {
 						  <OutputEncoding>UTF-8</OutputEncoding>
 						  <Image type=\"image/x-icon\" width=\"16\" height=\"16\">favicon.ico</Image>
 						  <Url type=\"application/atom+xml\" template=\"/opds/books?query={searchTerms}\"/>
+						  <Url type=\"application/opds+json\" template=\"/opds/v2/books?query={searchTerms}\"/>
+						  <Url type=\"application/x-suggestions+json\" template=\"/api/suggest?q={searchTerms}&amp;format=opensearch\"/>
 						  <Query role=\"example\" searchTerms=\"robot\"/>
 						</OpenSearchDescription>").with_additional_header("Access-Control-Allow-Origin", "*")
 					},
+					(GET) (/api/book/{id: i64}/similar) => {
+						let limit = match request.get_param("limit").unwrap_or_else(|| "10".to_string()).parse::<usize>() {
+							Ok(lim) => lim,
+							Err(_) => return self.get_json_error_response("Type error", "\"limit\" should have an integer argument"),
+						};
+						match self.reader.similar_books(id, limit) {
+							Ok(Some(result)) => Response::from_data("application/json", result.to_json()).with_additional_header("Access-Control-Allow-Origin", "*"),
+							Ok(None) => Response::empty_404(),
+							Err(e) => {
+								println!("Error searching tantivy: {}", e);
+								self.get_json_error_response("Server error", "There was a server side error.").with_status_code(500)
+							}
+						}
+					},
+					(GET) (/opds/similar/{id: i64}) => {
+						self.opds_similar(request, id, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/similar/{id: i64}) => {
+						self.opds_similar(request, id, OpdsFormat::Json)
+					},
 					(GET) (/api/book/{book: String}) => {
-						let maybe_id = if book.ends_with(".epub") {
-							&book[..book.len()-5]
-						} else {
-							&book
+						//links may carry the format's extension so readers know what they are getting
+						let maybe_id = match book.split_once('.') {
+							Some((id, _)) => id,
+							None => &book,
 						};
 						let id:i64 = match maybe_id.parse() {
 							Ok(num) => num,
 							Err(_) => {println!("Invalid book id passed to /api/book/ (not a number)"); return Response::empty_404()}
 						};
-						return match self.reader.get_book(id) {
+						return match self.get_book(id) {
 							Some(doc) => {
 								let mut f = match File::open(doc.file) {
 									Ok(f) => f,
@@ -186,89 +322,60 @@ impl Server {
 									Ok(_) => (),
 									Err(_) => {println!("Could not read all of book {} from file system.", id); return Response::empty_404()},
 								}
-								Response::from_data("application/epub+zip", buffer).with_additional_header("Access-Control-Allow-Origin", "*")
-																							.with_content_disposition_attachment(&format!("{} - {}",
+								let extension = BookFormat::from_mime(&doc.mime).map(|format| format.extension()).unwrap_or("epub");
+								Response::from_data(doc.mime, buffer).with_additional_header("Access-Control-Allow-Origin", "*")
+																							.with_content_disposition_attachment(&format!("{} - {}.{}",
 																							doc.creator.unwrap_or("unknown".to_string()),
-																							doc.title.unwrap_or("unknown author".to_string())))
+																							doc.title.unwrap_or("unknown author".to_string()),
+																							extension))
 							},
 							None => Response::empty_404(),
 						}
 					},
 					(GET) (/opds) => {
-						//in this case we return only root nav entries:
-						//Authors, Tags, Year of Publication, Author, Titles
-						let navs = vec!(
-							OpdsCategory::new("Authors".to_string(), "/opds/authors".to_string()),
-							OpdsCategory::new("Tags".to_string(), "/opds/tags".to_string()),
-							OpdsCategory::new("Year of Publication".to_string(), "".to_string()),
-							OpdsCategory::new("Titles".to_string(), "".to_string()),
-						);
-
-						let mut buf = Vec::new();
-						match templates::opds_html(&mut buf, &OpdsPage {id:"1".to_string(),date:"2021-01-21T10:56:30+01:00".to_string(),title:"ShelfControl".to_string(),url:"localhost:8000".to_string()}, &None, &Some(navs)) {
-							Ok(_) => return Response::from_data("application/xml", buf),
-							Err(e) => {println!("Error {:?}", e);self.get_json_error_response("OPDS error", "OPDS Error")},
-						}
-
+						self.opds_root(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2) => {
+						self.opds_root(request, OpdsFormat::Json)
 					},
 					(GET) (/opds/authors) => {
-						let cat_param = &request.get_param("categorise");
-						let (cat_str, query) = match cat_param {
-							Some(cat) => (cat.to_string(), None),
-							None => ("".to_string(), Some("*"))
-						};
-
-						let (results, by_author) = match &request.get_param("byAuthor") {
-							Some(_) => (self.reader.count_by_field("creator", &cat_str), true),
-							None => (self.reader.categorise("creator", &cat_str, query, 100), false),
-						};
-
-						//call categorise
-						let search_result = match results {
-							Ok(result) => result,
-							Err(e) => {
-								println!("Error:{:?}", e);
-								return self.get_json_error_response("Author search error", "Author search error")
-							}, //FIXME opds error response!
-						};
-
-						//populate OpdsCategory navs, for each search result
-						let navs:Vec<OpdsCategory> = search_result.categories.iter().map(|cat| {
-							let url = if by_author {
-									format!("/opds/books?query=creator:{}", encode(cat.prefix.trim()))
-								} else if cat.count>2000 {
-									format!("/opds/authors?categorise={}", cat.prefix.trim())
-								} else {
-									format!("/opds/authors?categorise={}&byAuthor=true", cat.prefix.trim())
-								};
-							OpdsCategory::new(format!( "{} ({})", cat.prefix, cat.count), url)
-						}).collect();
-
-						let mut buf = Vec::new();
-						match templates::opds_html(&mut buf, &OpdsPage {id:"1".to_string(),date:"2021-01-21T10:56:30+01:00".to_string(),title:"ShelfControl".to_string(),url:"localhost:8000".to_string()}, &None, &Some(navs)) {
-							Ok(_) => return Response::from_data("application/xml", buf),
-							Err(e) => {println!("Error {:?}", e);self.get_json_error_response("OPDS error", "OPDS Error")},
-						}
+						self.opds_authors(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/authors) => {
+						self.opds_authors(request, OpdsFormat::Json)
 					},
 					(GET) (/opds/books) => {
-						let query_param = &request.get_param("query");
-
-						let query_str = match query_param {
-							Some(query) => query,
-							None => return self.get_json_error_response("Query error", "\"query\" should be provided when performing a query") //FIXME opds error
-						}.trim();
-
-						let mut buf = Vec::new();
-						match templates::opds_html(&mut buf, &OpdsPage {id:"1".to_string(),date:"2021-01-21T10:56:30+01:00".to_string(),title:"ShelfControl".to_string(),url:"localhost:8000".to_string()}, &self.reader.search(query_str, 0, 2000).ok(), &None) {
-							Ok(_) => return Response::from_data("application/xml", buf),
-							Err(e) => {println!("Error {:?}", e);self.get_json_error_response("OPDS error", "OPDS Error")},
-						}
+						self.opds_books(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/books) => {
+						self.opds_books(request, OpdsFormat::Json)
+					},
+					(GET) (/opds/languages) => {
+						self.opds_languages(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/languages) => {
+						self.opds_languages(request, OpdsFormat::Json)
+					},
+					(GET) (/opds/years) => {
+						self.opds_years(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/years) => {
+						self.opds_years(request, OpdsFormat::Json)
+					},
+					(GET) (/opds/series) => {
+						self.opds_series(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/series) => {
+						self.opds_series(request, OpdsFormat::Json)
 					},
 					(GET) (/opds/tags) => {
-						unimplemented!()
+						self.opds_tags(request, OpdsFormat::Atom)
+					},
+					(GET) (/opds/v2/tags) => {
+						self.opds_tags(request, OpdsFormat::Json)
 					},
 					(GET) (/img/{id: i64}) => {
-						return match self.reader.get_book(id) {
+						return match self.get_book(id) {
 							Some(doc) => {
 								if self.use_coverdir {
 									let mime = match doc.cover_mime {
@@ -279,7 +386,7 @@ impl Server {
 										return Response::empty_404();
 									}
 
-									let mut imgfile = match File::open(format!("{}/{}",self.coverdir,id)) {
+									let mut imgfile = match File::open(format!("{}/{}",self.coverdir,doc.id)) {
 										Ok(file) => file,
 										Err(_) => {println!("Could not open img {}.", id); return Response::empty_404()},
 									};
@@ -292,36 +399,417 @@ impl Server {
 									}
 								} else {
 									//ok doing it inline like this for a very low use server
-									let mut epub = match EpubDoc::new(doc.file) {
-										Ok(epub) => epub,
-										Err(_) => return Response::empty_404(),
+									let format = match BookFormat::from_mime(&doc.mime) {
+										Some(format) => format,
+										None => return Response::empty_404(),
 									};
-									match epub.get_cover() {
-										Some(cover) => {
-												let cover_id_opt = &epub.get_cover_id();
-												let cover_id = match cover_id_opt {
-													Some(id) => id,
-													None => {println!("No cover id in book {}", id); return Response::empty_404()},
-												};
-												let mime = match epub.get_resource_mime(cover_id) {
-													Some(mime) => mime,
-													None => {println!("No mime in book {}", id); return Response::empty_404()},
-												};
-												Response::from_data(mime, cover.0).with_additional_header("Access-Control-Allow-Origin", "*")
-											},
-										None => Response::empty_404(),
+									match extract_cover(&doc.file, format) {
+										Some((cover, mime)) => Response::from_data(mime, cover).with_additional_header("Access-Control-Allow-Origin", "*"),
+										None => {println!("No cover in book {}", id); Response::empty_404()},
 									}
 								}
 							},
 							None => Response::empty_404(),
 						}
 					},
-					_ => Response::empty_404()
+					_ => {
+						//a DOI has a slash in it so the value can't be matched as a single path segment
+						match request.url().strip_prefix("/api/book/by-identifier/").and_then(|rest| rest.split_once('/')) {
+							Some((scheme, value)) if request.method() == "GET" => self.books_by_identifier(scheme, value),
+							_ => Response::empty_404()
+						}
+					}
 				)
 			})
 		})
 	}
 
+	//Look a book up by id, following the id registry's aliases so old links keep working
+	fn get_book(&self, id: i64) -> Option<BookMetadata> {
+		self.reader
+			.get_book(id)
+			.or_else(|| self.sqlite.get_current_id(id).and_then(|current_id| self.reader.get_book(current_id)))
+	}
+
+	//    /api/book/by-identifier/{scheme}/{value}
+	fn books_by_identifier(&self, scheme: &str, value: &str) -> Response {
+		let identifier = match identifier::classify(value, Some(scheme)) {
+			Some(identifier) => identifier,
+			None => return self.get_json_error_response("Identifier error", &format!("\"{}\" is not a valid {} identifier", value, scheme)),
+		};
+		match self.reader.books_by_identifier(&identifier) {
+			Ok(result) if result.count > 0 => Response::from_data("application/json", result.to_json()).with_additional_header("Access-Control-Allow-Origin", "*"),
+			Ok(_) => Response::empty_404(),
+			Err(e) => {
+				println!("Error searching tantivy: {}", e);
+				self.get_json_error_response("Server error", "There was a server side error.").with_status_code(500)
+			}
+		}
+	}
+
+	//    /opds/similar/{id}
+	fn opds_similar(&self, request: &Request, id: i64, format: OpdsFormat) -> Response {
+		match self.reader.similar_books(id, self.page_size) {
+			Ok(Some(result)) => self.opds_feed(request, format, Some(result), None),
+			Ok(None) => Response::empty_404(),
+			Err(e) => {println!("Error {:?}", e); self.get_json_error_response("OPDS error", "OPDS Error")},
+		}
+	}
+
+	//    /opds
+	fn opds_root(&self, request: &Request, format: OpdsFormat) -> Response {
+		//in this case we return only root nav entries:
+		//Authors, Tags, Series, Languages, Year of Publication, Author, Titles
+		let navs = vec!(
+			OpdsCategory::new("Authors".to_string(), format.url("/authors")),
+			OpdsCategory::new("Tags".to_string(), format.url("/tags")),
+			OpdsCategory::new("Series".to_string(), format.url("/series")),
+			OpdsCategory::new("Languages".to_string(), format.url("/languages")),
+			OpdsCategory::new("Year of Publication".to_string(), format.url("/years")),
+			OpdsCategory::new("Titles".to_string(), "".to_string()),
+		);
+
+		if format == OpdsFormat::Atom {
+			return self.opds_feed(request, format, None, Some(navs));
+		}
+
+		//OPDS 2 readers show groups on the front page, so the newest books are there too
+		let newest = match self.reader.search("*", 0, NEWEST_LISTED, &SearchOptions { sort: Sort::parse("newest", None).unwrap_or_default(), ..Default::default() }) {
+			Ok(newest) => newest,
+			Err(e) => {
+				println!("Error {:?}", e);
+				return self.get_json_error_response("OPDS error", "OPDS Error")
+			},
+		};
+		let (page, navs) = self.opds_page(request, &None, Some(navs));
+		let mut feed = Feed::new(&page, &None, &navs, &format.url(""));
+		feed.groups.push(Group {
+			metadata: FeedMetadata::new("Newest"),
+			links: vec![Link::new("self", format.url("/books?query=*&sort=newest"), OPDS_JSON)],
+			navigation: vec![],
+			publications: newest.payload.iter().map(|book| Publication::new(book, &format.url(""))).collect(),
+		});
+		self.opds2_response(&feed)
+	}
+
+	//    /opds/authors
+	fn opds_authors(&self, request: &Request, format: OpdsFormat) -> Response {
+		let cat_param = &request.get_param("categorise");
+		let (cat_str, query) = match cat_param {
+			Some(cat) => (cat.to_string(), None),
+			None => ("".to_string(), Some("*"))
+		};
+
+		let (results, by_author) = match &request.get_param("byAuthor") {
+			Some(_) => (self.reader.count_by_field("creator", &cat_str), true),
+			None => (self.reader.categorise("creator", &cat_str, query, 100), false),
+		};
+
+		//call categorise
+		let search_result = match results {
+			Ok(result) => result,
+			Err(e) => {
+				println!("Error:{:?}", e);
+				return self.get_json_error_response("Author search error", "Author search error")
+			}, //FIXME opds error response!
+		};
+
+		//populate OpdsCategory navs, for each search result
+		let navs:Vec<OpdsCategory> = search_result.categories.iter().map(|cat| {
+			let url = if by_author {
+					format.url(&format!("/books?query=creator:{}", encode(cat.prefix.trim())))
+				} else if cat.count>2000 {
+					format.url(&format!("/authors?categorise={}", cat.prefix.trim()))
+				} else {
+					format.url(&format!("/authors?categorise={}&byAuthor=true", cat.prefix.trim()))
+				};
+			OpdsCategory::new(format!( "{} ({})", cat.prefix, cat.count), url)
+		}).collect();
+
+		self.opds_feed(request, format, None, Some(navs))
+	}
+
+	//    /opds/books
+	fn opds_books(&self, request: &Request, format: OpdsFormat) -> Response {
+		let query_param = &request.get_param("query");
+
+		let query_str = match query_param {
+			Some(query) => query,
+			None => return self.get_json_error_response("Query error", "\"query\" should be provided when performing a query") //FIXME opds error
+		}.trim();
+
+		let sort = match self.get_sort(request) {
+			Some(sort) => sort,
+			None => return self.get_json_error_response("Sort error", "\"sort\" should be one of relevance, title, author, modtime, pubdate or filesize, and \"order\" asc or desc"),
+		};
+
+		let after = match request.get_param("cursor") {
+			Some(cursor) => match Cursor::parse(&cursor) {
+				Some(cursor) => Some(cursor),
+				None => return self.get_json_error_response("Cursor error", "\"cursor\" should be as given in a next link"),
+			},
+			None => None,
+		};
+		let before = match request.get_param("before") {
+			Some(cursor) => match Cursor::parse(&cursor) {
+				Some(cursor) => Some(cursor),
+				None => return self.get_json_error_response("Cursor error", "\"before\" should be as given in a previous link"),
+			},
+			None => None,
+		};
+
+		//narrowed down by tag, author, publisher, lang or decade, as tag navigation does
+		let filters = FACET_KINDS.iter().filter_map(|kind| request.get_param(kind.name()).map(|value| (*kind, value))).collect();
+
+		//facets are counted over everything found, so once is enough - on the first page
+		let start = self.get_start(request);
+		let facets = start == 0 && after.is_none() && before.is_none();
+		match self.reader.search(query_str, start, self.page_size, &SearchOptions { highlight: true, sort, after, before, filters, facets, ..Default::default() }) {
+			Ok(result) => self.opds_feed(request, format, Some(result), None),
+			Err(e) => {println!("Error {:?}", e);self.get_json_error_response("OPDS error", "OPDS Error")},
+		}
+	}
+
+	//    /opds/languages
+	fn opds_languages(&self, request: &Request, format: OpdsFormat) -> Response {
+		let languages = match self.sqlite.get_counts::<LanguageCount>(true, false, 0, 1000, None) {
+			Ok(languages) => languages,
+			Err(e) => {
+				println!("Error:{:?}", e);
+				return self.get_json_error_response("Language error", "Unable to query language counts")
+			},
+		};
+
+		//most books first, since that's usually the language the reader is after
+		let navs:Vec<OpdsCategory> = languages.payload.iter().map(|l| {
+			OpdsCategory::new(format!("{} ({})", language_name(&l.language), l.count), format.url(&format!("/books?query={}", encode(&format!("lang:{}", l.language)))))
+		}).collect();
+
+		self.opds_feed(request, format, None, Some(navs))
+	}
+
+	//    /opds/years
+	fn opds_years(&self, request: &Request, format: OpdsFormat) -> Response {
+		//decades first, then the years within the chosen one
+		let decade = match request.get_param("decade").map(|decade| decade.parse::<i64>()) {
+			Some(Ok(decade)) => Some(decade),
+			Some(Err(_)) => return self.get_json_error_response("Type error", "\"decade\" should have an integer argument"),
+			None => None,
+		};
+
+		let search_result = match self.reader.year_counts(decade) {
+			Ok(result) => result,
+			Err(e) => {
+				println!("Error:{:?}", e);
+				return self.get_json_error_response("Year search error", "Year search error")
+			}, //FIXME opds error response!
+		};
+
+		let navs:Vec<OpdsCategory> = search_result.categories.iter().map(|cat| {
+			match decade {
+				Some(_) => OpdsCategory::new(format!("{} ({})", cat.prefix, cat.count), format.url(&format!("/books?query={}", encode(&format!("pubdate:{}", cat.prefix))))),
+				None => OpdsCategory::new(format!("{}s ({})", cat.prefix, cat.count), format.url(&format!("/years?decade={}", cat.prefix))),
+			}
+		}).collect();
+
+		self.opds_feed(request, format, None, Some(navs))
+	}
+
+	//    /opds/series
+	fn opds_series(&self, request: &Request, format: OpdsFormat) -> Response {
+		//with a name we list that series' books in reading order, otherwise every series
+		match request.get_param("name") {
+			Some(name) => {
+				let books = match self.reader.series_books(name.trim()) {
+					Ok(books) => books,
+					Err(e) => {
+						println!("Error:{:?}", e);
+						return self.get_json_error_response("Series search error", "Series search error")
+					}, //FIXME opds error response!
+				};
+				self.opds_feed(request, format, Some(self.page_of(books, self.get_start(request))), None)
+			},
+			None => {
+				let series = match self.sqlite.get_counts::<SeriesCount>(false, true, 0, u32::MAX, None) {
+					Ok(series) => series,
+					Err(e) => {
+						println!("Error:{:?}", e);
+						return self.get_json_error_response("Series error", "Unable to query series counts")
+					},
+				};
+				let navs:Vec<OpdsCategory> = series.payload.iter().map(|s| {
+					OpdsCategory::new(format!("{} ({})", s.series, s.count), format.url(&format!("/series?name={}", encode(&s.series))))
+				}).collect();
+				self.opds_feed(request, format, None, Some(navs))
+			},
+		}
+	}
+
+	//    /opds/tags
+	fn opds_tags(&self, request: &Request, format: OpdsFormat) -> Response {
+		let prefix = request.get_param("categorise").unwrap_or_default();
+		let navs = match tag_navs(&self.sqlite, &prefix, format) {
+			Ok(navs) => navs,
+			Err(e) => {
+				println!("Error:{:?}", e);
+				return self.get_json_error_response("Tag error", "Unable to query tag counts")
+			},
+		};
+
+		self.opds_feed(request, format, None, Some(navs))
+	}
+
+	//Render one page of an OPDS feed of either books or navigation entries
+	fn opds_feed(&self, request: &Request, format: OpdsFormat, result: Option<SearchResult<BookMetadata>>, navs: Option<Vec<OpdsCategory>>) -> Response {
+		let (page, navs) = self.opds_page(request, &result, navs);
+		match format {
+			OpdsFormat::Atom => {
+				let mut buf = Vec::new();
+				match templates::opds_html(&mut buf, &page, &result, &navs) {
+					Ok(_) => Response::from_data("application/xml", buf),
+					Err(e) => {
+						println!("Error {:?}", e);
+						self.get_json_error_response("OPDS error", "OPDS Error")
+					}
+				}
+			}
+			OpdsFormat::Json => self.opds2_response(&Feed::new(&page, &result, &navs, &format.url(""))),
+		}
+	}
+
+	//One page of a feed, with links to the first, previous and next pages, and for navigation feeds the last.
+	//Books are already paged by the search, back and forth from cursors; navigation entries are all given and paged here.
+	fn opds_page(&self, request: &Request, result: &Option<SearchResult<BookMetadata>>, navs: Option<Vec<OpdsCategory>>) -> (OpdsPage, Option<Vec<OpdsCategory>>) {
+		let start = self.get_start(request);
+		let (total, navs) = match (result, navs) {
+			(Some(result), _) => (result.count, None),
+			(None, Some(navs)) => (navs.len(), Some(navs.into_iter().skip(start).take(self.page_size).collect::<Vec<OpdsCategory>>())),
+			(None, None) => (0, None),
+		};
+
+		//a cursor only leads on from this page, or back from it, so start is carried along just to number the page
+		let query_string = without_param(&without_param(request.raw_query_string(), "cursor"), "before");
+		let link = |rel: &str, start: usize, cursor: Option<(&str, &str)>| {
+			let mut query_string = with_param(&query_string, "start", &start.to_string());
+			if let Some((name, cursor)) = cursor {
+				query_string = with_param(&query_string, name, cursor);
+			}
+			OpdsLink { rel: rel.to_string(), href: format!("{}?{}", request.url(), query_string) }
+		};
+		let previous_start = start.saturating_sub(self.page_size);
+		let mut links = vec![link("first", 0, None)];
+		match result {
+			Some(result) => {
+				if let Some(previous_cursor) = &result.previous_cursor {
+					links.push(link("previous", previous_start, Some(("before", previous_cursor))));
+				}
+				if let Some(cursor) = &result.cursor {
+					links.push(link("next", start + self.page_size, Some(("cursor", cursor))));
+				}
+			}
+			None => {
+				let last = total.saturating_sub(1) / self.page_size * self.page_size;
+				if start > 0 {
+					links.push(link("previous", previous_start.min(last), None));
+				}
+				if start + self.page_size < total {
+					links.push(link("next", start + self.page_size, None));
+				}
+				links.push(link("last", last, None));
+			}
+		}
+
+		let page = OpdsPage {
+			id: "1".to_string(),
+			date: "2021-01-21T10:56:30+01:00".to_string(),
+			title: "ShelfControl".to_string(),
+			url: request.raw_url().to_string(),
+			kind: if result.is_some() { "acquisition" } else { "navigation" },
+			links,
+			total_results: total,
+			items_per_page: self.page_size,
+			start_index: start + 1,
+			facets: result.as_ref().filter(|result| result.facets.is_some()).map(|result| self.opds_facets(request, result)).unwrap_or_default(),
+		};
+		(page, navs)
+	}
+
+	//Links that order the same search differently, or narrow it down to the books with one tag, language, format etc,
+	//starting again from the first page. The current choices are marked active.
+	fn opds_facets(&self, request: &Request, result: &SearchResult<BookMetadata>) -> Vec<OpdsFacet> {
+		let query_string = without_param(&without_param(&without_param(request.raw_query_string(), "cursor"), "before"), "start");
+		let facet = |group: &str, title: String, name: &str, value: &str, count: Option<u64>, active: bool| OpdsFacet {
+			group: group.to_string(),
+			title,
+			href: format!("{}?{}", request.url(), with_param(&query_string, name, value)),
+			count,
+			active,
+		};
+
+		let sort = self.get_sort(request).unwrap_or_default();
+		let mut facets: Vec<OpdsFacet> = SORT_FACETS
+			.iter()
+			.map(|(title, name)| facet("Sort", title.to_string(), "sort", name, None, Sort::parse(name, None) == Some(sort)))
+			.collect();
+		for group in result.facets.iter().flatten() {
+			let chosen = request.get_param(&group.facet);
+			for value in &group.values {
+				let active = chosen.as_ref().is_some_and(|chosen| chosen.eq_ignore_ascii_case(&value.value));
+				facets.push(facet(&facet_name(&group.facet), facet_title(&group.facet, &value.value), &group.facet, &value.value, Some(value.count), active));
+			}
+		}
+		facets
+	}
+
+	fn opds2_response(&self, feed: &Feed) -> Response {
+		match serde_json::to_string(feed) {
+			Ok(json) => Response::from_data(OPDS_JSON, json).with_additional_header("Access-Control-Allow-Origin", "*"),
+			Err(e) => {
+				println!("Error {:?}", e);
+				self.get_json_error_response("OPDS error", "OPDS Error")
+			}
+		}
+	}
+
+	//Where a page of an OPDS feed starts, from the "start" param
+	fn get_start(&self, request: &Request) -> usize {
+		request.get_param("start").and_then(|start| start.parse::<usize>().ok()).unwrap_or(0)
+	}
+
+	//One page of books that were all found at once
+	fn page_of(&self, mut result: SearchResult<BookMetadata>, start: usize) -> SearchResult<BookMetadata> {
+		result.payload = result.payload.into_iter().skip(start).take(self.page_size).collect();
+		result.start = start;
+		result
+	}
+
+	//From the "sort" and "order" params, by relevance if there's no sort given. None if they make no sense.
+	fn get_sort(&self, request: &Request) -> Option<Sort> {
+		match request.get_param("sort") {
+			Some(sort) => Sort::parse(&sort, request.get_param("order").as_deref()),
+			None => Some(Sort::default()),
+		}
+	}
+
+	//Suggestions from the books indexed so far, building them again first if the index has changed since
+	fn suggestions(&self, prefix: &str, limit: usize) -> SearchResult<Suggestion> {
+		let generation = self.reader.generation();
+		if self.suggester.read().unwrap().generation != generation {
+			let mut suggester = self.suggester.write().unwrap();
+			//another request may have got here first
+			if suggester.generation != generation {
+				match Suggester::build(&self.sqlite, &self.reader) {
+					Ok(built) => *suggester = built,
+					Err(e) => {
+						println!("Could not build suggestions again, suggesting from before until the index next changes: {}", e);
+						suggester.generation = generation;
+					}
+				}
+			}
+		}
+		self.suggester.read().unwrap().suggest(prefix, limit)
+	}
+
 	fn get_json_error_response(&self, name: &str, msg: &str) -> Response {
 		Response::from_data(
 			"application/json",
@@ -336,3 +824,127 @@ impl Server {
 		.with_additional_header("Access-Control-Allow-Origin", "*")
 	}
 }
+
+//Tags starting with the prefix, or if there are too many of those to list, buckets of them by their next letter.
+//Each tag leads to its books. Only what's listed is read from sqlite, however many tags there are.
+fn tag_navs(sqlite: &Sqlite, prefix: &str, format: OpdsFormat) -> Result<Vec<OpdsCategory>, rusqlite::Error> {
+	//tags are all lower case, but not trimmed as a bucket may end in a space
+	let prefix = prefix.to_lowercase();
+	let tag_nav = |tag: &TagCount| {
+		OpdsCategory::new(format!("{} ({})", tag.tag, tag.count), format.url(&format!("/books?query=*&tag={}", encode(&tag.tag))))
+	};
+	if sqlite.count_starting::<TagCount>(&prefix)? as usize <= MAX_TAGS_LISTED {
+		return Ok(sqlite.get_counts_starting::<TagCount>(&prefix, None)?.iter().map(tag_nav).collect());
+	}
+
+	//a tag no longer than the prefix can't go in a bucket any deeper, so is listed as it is
+	let depth = prefix.chars().count() + 1;
+	let mut navs: Vec<OpdsCategory> = sqlite.get_counts_starting::<TagCount>(&prefix, Some(depth))?.iter().map(tag_nav).collect();
+	navs.extend(sqlite.bucket_counts::<TagCount>(&prefix, depth)?.iter().map(|(bucket, count)| {
+		OpdsCategory::new(format!("{} ({} tags)", bucket.to_uppercase(), count), format.url(&format!("/tags?categorise={}", encode(bucket))))
+	}));
+	Ok(navs)
+}
+
+//eg "Languages" for the lang facet
+fn facet_name(facet: &str) -> String {
+	match facet {
+		"tag" => "Tags".to_string(),
+		"author" => "Authors".to_string(),
+		"publisher" => "Publishers".to_string(),
+		"lang" => "Languages".to_string(),
+		"decade" => "Decades".to_string(),
+		"format" => "Formats".to_string(),
+		_ => facet.to_string(),
+	}
+}
+
+//The language facet reads better by name than by code
+fn facet_title(facet: &str, value: &str) -> String {
+	match facet {
+		"lang" => language_name(value),
+		"decade" => format!("{}s", value),
+		_ => value.to_string(),
+	}
+}
+
+//The same query string with one param replaced, for links to other pages of a feed
+fn with_param(query_string: &str, name: &str, value: &str) -> String {
+	let mut params = without_param(query_string, name);
+	if !params.is_empty() {
+		params.push('&');
+	}
+	params.push_str(&format!("{}={}", name, encode(value)));
+	params
+}
+
+fn without_param(query_string: &str, name: &str) -> String {
+	query_string
+		.split('&')
+		.filter(|param| !param.is_empty() && param.split('=').next() != Some(name))
+		.collect::<Vec<&str>>()
+		.join("&")
+}
+
+#[test]
+fn test_with_param() {
+	assert_eq!("query=dickens&start=50", with_param("query=dickens&start=0", "start", "50"));
+	assert_eq!("start=50", with_param("", "start", "50"));
+	assert_eq!("query=dickens&tag=science%20fiction", with_param("query=dickens", "tag", "science fiction"));
+	assert_eq!("query=dickens", without_param("query=dickens&cursor=abc", "cursor"));
+}
+
+#[test]
+fn test_tag_navs() {
+	//a db of its own, cleared first in case an earlier run was interrupted, and removed however this one ends
+	struct DbCleanup(String);
+	impl DbCleanup {
+		fn clear(&self) {
+			let _ = std::fs::remove_dir_all(&self.0);
+			let _ = std::fs::remove_file(Sqlite::ids_file(&self.0));
+		}
+	}
+	impl Drop for DbCleanup {
+		fn drop(&mut self) {
+			self.clear();
+		}
+	}
+	let db_cleanup = DbCleanup(std::env::temp_dir().join(format!("shelfcontrol_tag_navs_{}", std::process::id())).to_string_lossy().to_string());
+	db_cleanup.clear();
+	let db_dir = &db_cleanup.0;
+	std::fs::create_dir_all(db_dir).unwrap();
+	let sqlite = Sqlite::new(db_dir).unwrap();
+	sqlite.make_db().unwrap();
+	let mut tags: std::collections::HashMap<String, u32> = (0..120).map(|i| (format!("фантастика {}", i), 1)).collect();
+	tags.insert("фэнтези".to_string(), 2);
+	tags.insert("ф".to_string(), 1);
+	tags.insert("horror".to_string(), 1);
+	sqlite.write_counts::<TagCount>(tags).unwrap();
+
+	let navs = |prefix: &str| -> Vec<(String, String)> {
+		tag_navs(&sqlite, prefix, OpdsFormat::Atom).unwrap().into_iter().map(|nav| (nav.title, nav.url)).collect()
+	};
+	//too many to list, so bucketed by their first letter, whatever script it is in
+	assert_eq!(
+		vec![
+			("H (1 tags)".to_string(), "/opds/tags?categorise=h".to_string()),
+			("Ф (122 tags)".to_string(), "/opds/tags?categorise=%D1%84".to_string()),
+		],
+		navs("")
+	);
+	//then their second, with a tag too short for a bucket listed as it is
+	assert_eq!(
+		vec![
+			("ф (1)".to_string(), "/opds/books?query=*&tag=%D1%84".to_string()),
+			("ФА (120 tags)".to_string(), "/opds/tags?categorise=%D1%84%D0%B0".to_string()),
+			("ФЭ (1 tags)".to_string(), "/opds/tags?categorise=%D1%84%D1%8D".to_string()),
+		],
+		navs("Ф")
+	);
+	//until few enough to list
+	assert_eq!(
+		vec![("фэнтези (2)".to_string(), "/opds/books?query=*&tag=%D1%84%D1%8D%D0%BD%D1%82%D0%B5%D0%B7%D0%B8".to_string())],
+		navs("фэ")
+	);
+
+}
@@ -3,7 +3,7 @@ use r2d2_sqlite::SqliteConnectionManager;
 use r2d2::Pool;
 use serde::Serialize;
 use std::collections::HashMap;
-use crate::{TagCount, AuthorCount, PublisherCount};
+use crate::{TagCount, AuthorCount, PublisherCount, SeriesCount, LanguageCount, BookMetadata, IndexedFile};
 use crate::search_result::SearchResult;
 pub trait DbInfo<T: std::fmt::Debug + Serialize> {
     fn new(key:String, count: u32) -> T;
@@ -61,31 +61,179 @@ impl DbInfo<PublisherCount> for PublisherCount {
     }
 }
 
+impl DbInfo<SeriesCount> for SeriesCount {
+    fn new(key:String, count:u32) -> SeriesCount {
+        SeriesCount {
+            series: key,
+            count,
+        }
+    }
+    fn get_table() -> String {
+        "series".to_string()
+    }
+
+    fn get_pkcol() -> String {
+        "series".to_string()
+    }
+}
+
+impl DbInfo<LanguageCount> for LanguageCount {
+    fn new(key:String, count:u32) -> LanguageCount {
+        LanguageCount {
+            language: key,
+            count,
+        }
+    }
+    fn get_table() -> String {
+        "languages".to_string()
+    }
+
+    fn get_pkcol() -> String {
+        "language".to_string()
+    }
+}
+
 pub struct Sqlite {
     pool: Pool<SqliteConnectionManager>,
+    //book ids live beside the index rather than in it, so that rebuilding the index from scratch keeps them
+    ids: Pool<SqliteConnectionManager>,
+}
+
+//Book ids handed out so far, so a book keeps its id when it is renamed, moved or has its metadata fixed
+#[derive(Default)]
+pub struct IdRegistry {
+    by_hash: HashMap<String, i64>,
+    by_file: HashMap<String, i64>,
+}
+
+impl IdRegistry {
+    //Identical content is the same book wherever it lives; failing that a file edited in place keeps its id
+    pub fn resolve(&self, content_hash: &str, file: &str) -> Option<i64> {
+        self.by_hash.get(content_hash).or_else(|| self.by_file.get(file)).copied()
+    }
+
+    pub fn add(&mut self, bm: &BookMetadata) {
+        if let Some(content_hash) = &bm.content_hash {
+            self.by_hash.insert(content_hash.clone(), bm.id);
+        }
+        self.by_file.insert(bm.file.clone(), bm.id);
+    }
+
+    //Books indexed before the registry existed have never been hashed, but their files still identify them
+    pub fn adopt(&mut self, indexed: &HashMap<String, IndexedFile>) {
+        for (file, indexed_file) in indexed {
+            self.by_file.entry(file.clone()).or_insert(indexed_file.id);
+        }
+    }
 }
 
 impl Sqlite {
-    pub fn new(dir: &String) -> Result<Sqlite, rusqlite::Error> {
-        let manager = SqliteConnectionManager::file(dir);
+    //db_dir is the index directory; counts go in it, ids next to it
+    pub fn new(db_dir: &String) -> Result<Sqlite, rusqlite::Error> {
+        let manager = SqliteConnectionManager::file(format!("{}/counts.sqlite", db_dir));
         let pool = r2d2::Pool::new(manager).unwrap();
+        let ids_manager = SqliteConnectionManager::file(Sqlite::ids_file(db_dir));
+        let ids = r2d2::Pool::new(ids_manager).unwrap();
 
         Ok(Sqlite { 
-            pool
+            pool,
+            ids
         })
     }
 
+    pub fn ids_file(db_dir: &str) -> String {
+        format!("{}.ids.sqlite", db_dir.trim_end_matches('/'))
+    }
+
     pub fn make_db(&self) -> Result<(), rusqlite::Error> {
         self.create_table::<AuthorCount>()?;
         self.create_table::<PublisherCount>()?;
         self.create_table::<TagCount>()?;
+        self.create_table::<SeriesCount>()?;
+        self.create_table::<LanguageCount>()?;
+
+        let conn = self.ids.get().unwrap();
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS book_ids (
+                    content_hash TEXT primary key,
+                    id INTEGER,
+                    file TEXT
+            )",
+    [],
+        )?;
+        conn.execute("CREATE INDEX IF NOT EXISTS book_ids_file_idx ON book_ids (file)", [])?;
+        //ids that used to refer to a book, mapped to the id it has now
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS id_aliases (
+                    old_id INTEGER primary key,
+                    id INTEGER
+            )",
+    [],
+        )?;
         Ok(())
     }
 
+    pub fn load_registry(&self) -> Result<IdRegistry, rusqlite::Error> {
+        let conn = self.ids.get().unwrap();
+        let mut registry = IdRegistry::default();
+
+        let mut stmt = conn.prepare("select content_hash, id, file from book_ids")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<String>>(2)?)))?;
+        for row in rows {
+            let (content_hash, id, file) = row?;
+            registry.by_hash.insert(content_hash, id);
+            if let Some(file) = file {
+                registry.by_file.insert(file, id);
+            }
+        }
+
+        Ok(registry)
+    }
+
+    //Record the ids of freshly indexed books. Where a book was known by another id, by its content or its file,
+    //that id becomes an alias, as does the id its metadata alone would hash to.
+    pub fn register_ids(&self, bms: &Vec<BookMetadata>) -> Result<(), rusqlite::Error> {
+        let mut conn = self.ids.get().unwrap();
+
+        let tx = conn.transaction()?;
+        {
+            let mut previous_stmt = tx.prepare("SELECT DISTINCT id FROM book_ids WHERE content_hash = ?1 OR file = ?2")?;
+            let mut unset_file_stmt = tx.prepare("UPDATE book_ids SET file = NULL WHERE file = ?1 AND content_hash != ?2")?;
+            let mut id_stmt = tx.prepare("INSERT INTO book_ids(content_hash, id, file) values (?1, ?2, ?3) ON CONFLICT(content_hash) DO UPDATE SET id = excluded.id, file = excluded.file")?;
+            let mut alias_stmt = tx.prepare("INSERT OR REPLACE INTO id_aliases(old_id, id) values (?1, ?2)")?;
+            //older aliases follow the book to its new id, and an id in use again is nobody's alias
+            let mut repoint_stmt = tx.prepare("UPDATE id_aliases SET id = ?2 WHERE id = ?1")?;
+            let mut unalias_stmt = tx.prepare("DELETE FROM id_aliases WHERE old_id = ?1")?;
+            for bm in bms {
+                let mut old_ids = vec![bm.hash_md()];
+                if let Some(content_hash) = &bm.content_hash {
+                    let previous = previous_stmt.query_map(params![content_hash, bm.file], |row| row.get::<_, i64>(0))?;
+                    for id in previous {
+                        old_ids.push(id?);
+                    }
+                    unset_file_stmt.execute(params![bm.file, content_hash])?;
+                    id_stmt.execute(params![content_hash, bm.id, bm.file])?;
+                }
+                unalias_stmt.execute(params![bm.id])?;
+                for old_id in old_ids.into_iter().filter(|old_id| *old_id != bm.id) {
+                    repoint_stmt.execute(params![old_id, bm.id])?;
+                    alias_stmt.execute(params![old_id, bm.id])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_current_id(&self, old_id: i64) -> Option<i64> {
+        let conn = self.ids.get().unwrap();
+        conn.query_row("select id from id_aliases where old_id = ?1", params![old_id], |row| row.get(0)).ok()
+    }
+
     fn create_table<T: DbInfo<T> + std::fmt::Debug + Serialize>(&self) -> Result<(), rusqlite::Error> {
         let conn = self.pool.get().unwrap();
         conn.execute(
-            &format!("CREATE TABLE {} (
+            &format!("CREATE TABLE IF NOT EXISTS {} (
                     {} TEXT primary key,
                     count INTEGER
             )", T::get_table(), T::get_pkcol() ), 
@@ -108,7 +256,28 @@ impl Sqlite {
             }
         }
         tx.commit()?;
-        conn.execute(&format!("CREATE UNIQUE INDEX {}_idx ON {} ({})", T::get_table(), T::get_table(), T::get_pkcol()), params![])?;
+        conn.execute(&format!("CREATE UNIQUE INDEX IF NOT EXISTS {}_idx ON {} ({})", T::get_table(), T::get_table(), T::get_pkcol()), params![])?;
+        Ok(())
+    }
+
+    //Apply the changes from an incremental scan to existing counts, dropping anything no longer referenced
+    pub fn update_counts<T: DbInfo<T> + std::fmt::Debug + Serialize>(&self, added: HashMap<String, u32>, removed: HashMap<String, u32>) -> Result<(), rusqlite::Error> {
+        let mut conn = self.pool.get().unwrap();
+
+        let tx = conn.transaction()?;
+        {
+            let mut add_stmt = tx.prepare(&format!("INSERT INTO {}({}, count) values (?1, ?2) ON CONFLICT({}) DO UPDATE SET count = count + excluded.count", T::get_table(), T::get_pkcol(), T::get_pkcol()))?;
+            for (key, count) in added {
+                add_stmt.execute(params![key,count])?;
+            }
+
+            let mut remove_stmt = tx.prepare(&format!("UPDATE {} SET count = count - ?2 WHERE {} = ?1", T::get_table(), T::get_pkcol()))?;
+            for (key, count) in removed {
+                remove_stmt.execute(params![key,count])?;
+            }
+        }
+        tx.execute(&format!("DELETE FROM {} WHERE count <= 0", T::get_table()), [])?;
+        tx.commit()?;
         Ok(())
     }
 
@@ -143,8 +312,43 @@ impl Sqlite {
             start: offset as usize,
             query: filter,
             payload,
+            facets: None,
+            cursor: None,
+            previous_cursor: None,
         })
     }
+    //Keys starting with prefix are a range of the primary key's index, which LIKE can't use. The upper bound is the prefix
+    //followed by the highest character there is.
+    fn prefix_range(prefix: &str) -> (String, String) {
+        (prefix.to_string(), format!("{}{}", prefix, char::MAX))
+    }
+
+    pub fn count_starting<T: DbInfo<T> + std::fmt::Debug + Serialize>(&self, prefix: &str) -> Result<u32, rusqlite::Error> {
+        let conn = self.pool.get().unwrap();
+        let (from, to) = Sqlite::prefix_range(prefix);
+        conn.query_row(&format!("select count(*) from {} where {} >= ?1 and {} < ?2", T::get_table(), T::get_pkcol(), T::get_pkcol()), params![from, to], |row| row.get(0))
+    }
+
+    //In key order, and if max_chars is given only keys shorter than that
+    pub fn get_counts_starting<T: DbInfo<T> + std::fmt::Debug + Serialize>(&self, prefix: &str, max_chars: Option<usize>) -> Result<Vec<T>, rusqlite::Error> {
+        let conn = self.pool.get().unwrap();
+        let (from, to) = Sqlite::prefix_range(prefix);
+        let mut stmt = conn.prepare(&format!("select {}, count from {} where {} >= ?1 and {} < ?2 and length({}) < ?3 order by {}", T::get_pkcol(), T::get_table(), T::get_pkcol(), T::get_pkcol(), T::get_pkcol(), T::get_pkcol()))?;
+        let max_chars = max_chars.unwrap_or(i32::MAX as usize) as i64;
+        let rows = stmt.query_map(params![from, to, max_chars], |row| Ok(T::new(row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
+    //Keys starting with prefix, grouped by their first chars characters, with how many keys are in each group. Keys too short
+    //for a group are left out.
+    pub fn bucket_counts<T: DbInfo<T> + std::fmt::Debug + Serialize>(&self, prefix: &str, chars: usize) -> Result<Vec<(String, u32)>, rusqlite::Error> {
+        let conn = self.pool.get().unwrap();
+        let (from, to) = Sqlite::prefix_range(prefix);
+        let mut stmt = conn.prepare(&format!("select substr({}, 1, ?3) as bucket, count(*) from {} where {} >= ?1 and {} < ?2 and length({}) >= ?3 group by bucket order by bucket", T::get_pkcol(), T::get_table(), T::get_pkcol(), T::get_pkcol(), T::get_pkcol()))?;
+        let rows = stmt.query_map(params![from, to, chars as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    }
+
     /*handy queries
     select * from tags where tag like "%lovecraft%" order by count desc limit 20,20;
     limit term is skip,count
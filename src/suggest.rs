@@ -0,0 +1,255 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::error::Error;
+
+use fst::{IntoStreamer, Map, Streamer};
+use tantivy::tokenizer::{AsciiFoldingFilter, LowerCaser, SimpleTokenizer, TextAnalyzer};
+
+use crate::search_result::SearchResult;
+use crate::sqlite::Sqlite;
+use crate::ttvy::TantivyReader;
+use crate::{AuthorCount, PublisherCount, SeriesCount, TagCount};
+
+pub const TITLE: &str = "title";
+pub const AUTHOR: &str = "author";
+pub const SERIES: &str = "series";
+pub const TAG: &str = "tag";
+pub const PUBLISHER: &str = "publisher";
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Suggestion {
+	pub text: String,
+	pub kind: &'static str,
+	pub count: u32,
+}
+
+//Completions for a search box as the user types. Every word of every title, author, series, tag and publisher starts a key
+//in an FST, so "dick" finds "Charles Dickens" as well as "Dick Francis", without touching sqlite or the index. The keys a
+//prefix matches are a run of neighbours in key order, so each key's value is its place in that order, and a tree over the
+//keys holds the best ranked key under each node. The best few matches are then found without looking at the others.
+pub struct Suggester {
+	map: Map<Vec<u8>>,
+	best: Vec<u32>, //a tree over the keys in key order, the leaves are each key's place in rank order from 1 (0 for no key)
+	ranked: Vec<u32>, //the suggestion of each place in rank order
+	suggestions: Vec<Suggestion>,
+	pub generation: u64, //of the index the suggestions were built from
+}
+
+impl Suggester {
+	pub fn build(sqlite: &Sqlite, reader: &TantivyReader) -> Result<Suggester, Box<dyn Error>> {
+		//taken first, so that anything indexed while building is picked up by the next build
+		let generation = reader.generation();
+		let mut suggestions = vec![];
+		for author in sqlite.get_counts::<AuthorCount>(false, true, 0, u32::MAX, None)?.payload {
+			suggestions.push(Suggestion {
+				text: author.creator,
+				kind: AUTHOR,
+				count: author.count,
+			});
+		}
+		for series in sqlite.get_counts::<SeriesCount>(false, true, 0, u32::MAX, None)?.payload {
+			suggestions.push(Suggestion {
+				text: series.series,
+				kind: SERIES,
+				count: series.count,
+			});
+		}
+		for tag in sqlite.get_counts::<TagCount>(false, true, 0, u32::MAX, None)?.payload {
+			suggestions.push(Suggestion {
+				text: tag.tag,
+				kind: TAG,
+				count: tag.count,
+			});
+		}
+		for publisher in sqlite.get_counts::<PublisherCount>(false, true, 0, u32::MAX, None)?.payload {
+			suggestions.push(Suggestion {
+				text: publisher.publisher,
+				kind: PUBLISHER,
+				count: publisher.count,
+			});
+		}
+		for (title, count) in reader.title_counts().map_err(|e| e.to_string())? {
+			suggestions.push(Suggestion {
+				text: title,
+				kind: TITLE,
+				count,
+			});
+		}
+		let mut suggester = Suggester::from_suggestions(suggestions)?;
+		suggester.generation = generation;
+		Ok(suggester)
+	}
+
+	fn from_suggestions(suggestions: Vec<Suggestion>) -> Result<Suggester, Box<dyn Error>> {
+		let mut analyzer = analyzer();
+		//the key is the words from that point on, then the suggestion it came from so that keys are unique
+		let mut keys: Vec<(Vec<u8>, usize, bool)> = vec![];
+		for (i, suggestion) in suggestions.iter().enumerate() {
+			let words = fold(&mut analyzer, &suggestion.text);
+			for start in 0..words.len() {
+				let mut key = words[start..].join(" ").into_bytes();
+				key.push(0);
+				key.extend_from_slice(&(i as u32).to_be_bytes());
+				keys.push((key, i, start == 0));
+			}
+		}
+		keys.sort();
+		keys.dedup();
+
+		//higher is better: the count, whether it starts with what was typed, then shorter and alphabetically first
+		let rank = |&(_, i, starts): &(Vec<u8>, usize, bool)| {
+			let suggestion: &Suggestion = &suggestions[i];
+			(suggestion.count, starts, Reverse(suggestion.text.len()), Reverse(suggestion.text.as_str()), Reverse(i))
+		};
+		let mut by_rank: Vec<usize> = (0..keys.len()).collect();
+		by_rank.sort_by(|a, b| rank(&keys[*a]).cmp(&rank(&keys[*b])));
+		let size = keys.len().next_power_of_two();
+		let mut best = vec![0; 2 * size];
+		let mut ranked = Vec::with_capacity(keys.len());
+		for (place, ordinal) in by_rank.into_iter().enumerate() {
+			best[size + ordinal] = place as u32 + 1;
+			ranked.push(keys[ordinal].1 as u32);
+		}
+		for node in (1..size).rev() {
+			best[node] = best[2 * node].max(best[2 * node + 1]);
+		}
+
+		let map = Map::from_iter(keys.into_iter().enumerate().map(|(ordinal, (key, _, _))| (key, ordinal as u64)))?;
+		Ok(Suggester {
+			map,
+			best,
+			ranked,
+			suggestions,
+			generation: 0,
+		})
+	}
+
+	//Most books first, then those that start with what was typed, then the shortest
+	pub fn suggest(&self, prefix: &str, limit: usize) -> SearchResult<Suggestion> {
+		let mut analyzer = analyzer();
+		let folded = fold(&mut analyzer, prefix).join(" ");
+		let mut payload: Vec<Suggestion> = vec![];
+		if !folded.is_empty() && limit > 0 {
+			//the keys from the first at or after the prefix, up to the first after everything starting with it
+			let start = self.ordinal_from(folded.as_bytes());
+			let end = match after_prefix(folded.as_bytes()) {
+				after if after.is_empty() => self.map.len(),
+				after => self.ordinal_from(&after),
+			};
+			//the best of the nodes covering those keys comes off the heap first, replaced by its children until it is a key.
+			//A suggestion may have several keys, but its best comes first.
+			let size = self.best.len() / 2;
+			let mut heap = BinaryHeap::new();
+			let (mut left, mut right) = (start + size, end + size);
+			while left < right {
+				if left % 2 == 1 {
+					heap.push((self.best[left], left));
+					left += 1;
+				}
+				if right % 2 == 1 {
+					right -= 1;
+					heap.push((self.best[right], right));
+				}
+				left /= 2;
+				right /= 2;
+			}
+			let mut seen = HashSet::new();
+			while let Some((place, node)) = heap.pop() {
+				if place == 0 {
+					break;
+				}
+				if node < size {
+					heap.push((self.best[2 * node], 2 * node));
+					heap.push((self.best[2 * node + 1], 2 * node + 1));
+				} else {
+					let i = self.ranked[place as usize - 1] as usize;
+					if seen.insert(i) {
+						payload.push(self.suggestions[i].clone());
+						if payload.len() == limit {
+							break;
+						}
+					}
+				}
+			}
+		}
+
+		SearchResult {
+			count: payload.len(),
+			start: 0,
+			query: Some(prefix.to_string()),
+			payload,
+			facets: None,
+			cursor: None,
+			previous_cursor: None,
+		}
+	}
+
+	//The place in key order of the first key at or after this one
+	fn ordinal_from(&self, key: &[u8]) -> usize {
+		match self.map.range().ge(key).into_stream().next() {
+			Some((_, ordinal)) => ordinal as usize,
+			None => self.map.len(),
+		}
+	}
+}
+
+//The first key after all those starting with this prefix, or empty if there is none
+fn after_prefix(prefix: &[u8]) -> Vec<u8> {
+	let mut after = prefix.to_vec();
+	while let Some(last) = after.pop() {
+		if last < u8::MAX {
+			after.push(last + 1);
+			break;
+		}
+	}
+	after
+}
+
+//The same folding as the index, so "cech" suggests "Čechov"
+fn analyzer() -> TextAnalyzer {
+	TextAnalyzer::builder(SimpleTokenizer::default())
+		.filter(LowerCaser)
+		.filter(AsciiFoldingFilter)
+		.build()
+}
+
+fn fold(analyzer: &mut TextAnalyzer, text: &str) -> Vec<String> {
+	let mut words = vec![];
+	let mut stream = analyzer.token_stream(text);
+	while let Some(token) = stream.next() {
+		words.push(token.text.clone());
+	}
+	words
+}
+
+#[test]
+fn test_suggest() {
+	let suggestion = |text: &str, kind: &'static str, count: u32| Suggestion {
+		text: text.to_string(),
+		kind,
+		count,
+	};
+	let suggester = Suggester::from_suggestions(vec![
+		suggestion("Charles Dickens", AUTHOR, 2),
+		suggestion("Dick Francis", AUTHOR, 2),
+		suggestion("Moby Dick", TITLE, 1),
+		suggestion("Anton Čechov", AUTHOR, 1),
+	])
+	.unwrap();
+
+	let texts = |prefix: &str| -> Vec<String> { suggester.suggest(prefix, 10).payload.into_iter().map(|s| s.text).collect() };
+	assert_eq!(vec!["Dick Francis", "Charles Dickens", "Moby Dick"], texts("dick"));
+	assert_eq!(vec!["Charles Dickens"], texts("charles di"));
+	assert_eq!(vec!["Anton Čechov"], texts("cech"));
+	assert!(texts("").is_empty());
+	assert!(texts("zola").is_empty());
+
+	//however many things a short prefix matches, the commonest are found
+	let mut suggestions: Vec<Suggestion> = (0..30000).map(|i| suggestion(&format!("A{}", i), TAG, 1)).collect();
+	suggestions.push(suggestion("Zebra Aardvark", TAG, 3));
+	suggestions.push(suggestion("Aardvark Zebra", TAG, 3));
+	suggestions.push(suggestion("Aardvarks", TAG, 5));
+	let suggester = Suggester::from_suggestions(suggestions).unwrap();
+	let texts: Vec<String> = suggester.suggest("a", 3).payload.into_iter().map(|s| s.text).collect();
+	assert_eq!(vec!["Aardvarks", "Aardvark Zebra", "Zebra Aardvark"], texts);
+}
@@ -1,13 +1,26 @@
 #[cfg(test)]
 mod test {
 
+	use crate::identifier;
 	use crate::scanner;
+	use crate::scanner::BookFormat;
+	use crate::search_result::SearchResult;
+	use crate::suggest;
+	use crate::suggest::Suggester;
+	use crate::BookMetadata;
+	use crate::BookWriter;
+	use crate::Contributor;
+	use crate::{AuthorCount, PublisherCount, SeriesCount, TagCount};
 	use crate::ttvy;
+	use crate::ttvy::{Cursor, FacetKind, SearchOptions, Sort};
 	use crate::Sqlite;
 	use serial_test::serial;
+	use std::collections::{HashMap, HashSet};
 	use std::fs;
 	use std::io::Error;
 	use std::{thread, time};
+	use ::time::macros::datetime;
+	use ::time::OffsetDateTime;
 
 	struct DirsCleanup;
 
@@ -20,6 +33,17 @@ mod test {
 	fn tidy() {
 		fs::remove_dir_all("target/images");
 		fs::remove_dir_all("target/index");
+		fs::remove_dir_all("target/library");
+		fs::remove_file(Sqlite::ids_file("target/index"));
+	}
+
+	fn scan_options(incremental: bool, fulltext: bool) -> scanner::ScanOptions {
+		scanner::ScanOptions {
+			coverdir: "target/images".to_string(),
+			use_coverdir: true,
+			incremental,
+			fulltext,
+		}
 	}
 
 	fn get_reader() -> Result<ttvy::TantivyReader, Error> {
@@ -27,27 +51,49 @@ mod test {
 		fs::create_dir("target/images")?;
 		//fs::create_dir("target/index")?;
 		let db_dir = &"target/index".to_string();
-		let writer = ttvy::TantivyWriter::new(db_dir).unwrap();
-		let sql_writer = Sqlite::new(&format!("{}/counts.sqlite", &db_dir)).unwrap();
-		scanner::scan_dirs(
-			["test/library".to_string()].to_vec(),
-			"target/images".to_string(),
-			true,
-			Box::new(writer),
-			sql_writer,
-		)
-		.expect("Scanner failed");
+		let mut writer = ttvy::TantivyWriter::new(db_dir).unwrap();
+		let sql_writer = Sqlite::new(db_dir).unwrap();
+		scanner::scan_dirs(&["test/library".to_string()].to_vec(), &scan_options(false, false), &mut writer, &sql_writer)
+			.expect("Scanner failed");
 		let reader = ttvy::TantivyReader::new("target/index".to_string()).expect("Reader failed");
 		Ok(reader)
 	}
 
+	//Start a test from nothing, and tidy up again however it ends
+	fn clean_dirs() -> DirsCleanup {
+		tidy();
+		DirsCleanup
+	}
+
+	//A book with just a title and a file of its own, for tests to fill in the rest of
+	fn book(id: i64, title: &str) -> BookMetadata {
+		BookMetadata {
+			id,
+			title: Some(title.to_string()),
+			file: format!("/books/{}.epub", id),
+			..Default::default()
+		}
+	}
+
+	//An index of only these books, without scanning any files. Each batch is committed as a segment of its own.
+	fn index_books(batches: &[Vec<BookMetadata>]) -> ttvy::TantivyReader {
+		let db_dir = &"target/index".to_string();
+		let mut writer = ttvy::TantivyWriter::new(db_dir).unwrap();
+		for batch in batches {
+			writer.write_epubs(batch).expect("Write failed");
+			writer.commit().expect("Commit failed");
+		}
+		drop(writer);
+		ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed")
+	}
+
 	#[test]
 	#[serial]
 	fn integration_test() -> Result<(), Error> {
 		tidy();
 		let _dirs_cleanup = DirsCleanup;
 		let reader = get_reader()?;
-		let mut result = reader.search("darwin", 0, 10).expect("Search failed");
+		let mut result = reader.search("darwin", 0, 10, &SearchOptions::default()).expect("Search failed");
 
 		println!("result: {}", result.to_json());
 		assert!(result.to_json().contains("\"count\":1,"));
@@ -64,14 +110,14 @@ mod test {
 		assert!(result.to_json().contains("\"moddate\":\"20"));
 		assert!(result.to_json().contains(",\"cover_mime\":\"image/jpeg\"}]}"));
 
-		result = reader.search("creator:\"Thomas de Quincey\"", 0, 10).expect("Search failed");
+		result = reader.search("creator:\"Thomas de Quincey\"", 0, 10, &SearchOptions::default()).expect("Search failed");
 		assert!(result.count == 1);
 		let book = result.payload.get(0).unwrap();
 		println!("{}", book.creator.as_ref().unwrap());
 		assert!(book.creator.as_ref().unwrap() == "Thomas De Quincey");
 		assert!(book.filesize == 115227);
 
-		result = reader.search("*", 0, 10).expect("Search failed");
+		result = reader.search("*", 0, 10, &SearchOptions::default()).expect("Search failed");
 		println!("Result count: {}", result.count);
 		assert!(result.count == 8);
 
@@ -142,4 +188,904 @@ mod test {
 
 		Ok(())
 	}
+
+	//A copy of the test library that tests can change without spoiling it for the others
+	fn copy_library() -> Result<(), Error> {
+		fs::create_dir_all("target/library")?;
+		for entry in fs::read_dir("test/library")? {
+			let path = entry?.path();
+			fs::copy(&path, format!("target/library/{}", path.file_name().unwrap().to_string_lossy()))?;
+		}
+		Ok(())
+	}
+
+	fn scan_library(incremental: bool) {
+		let db_dir = &"target/index".to_string();
+		let mut writer = if incremental { ttvy::TantivyWriter::open(db_dir).unwrap() } else { ttvy::TantivyWriter::new(db_dir).unwrap() };
+		let sql_writer = Sqlite::new(db_dir).unwrap();
+		scanner::scan_dirs(&["target/library".to_string()].to_vec(), &scan_options(incremental, false), &mut writer, &sql_writer)
+			.expect("Scan failed");
+	}
+
+	fn author_count(sqlite: &Sqlite, author: &str) -> u32 {
+		let authors = sqlite.get_counts::<AuthorCount>(false, true, 0, 100, Some(author.to_string())).expect("Author counts failed");
+		authors.payload.iter().map(|a| a.count).sum()
+	}
+
+	fn tag_count(sqlite: &Sqlite, tag: &str) -> u32 {
+		let tags = sqlite.get_counts::<TagCount>(false, true, 0, 1000, Some(tag.to_string())).expect("Tag counts failed");
+		tags.payload.iter().filter(|t| t.tag == tag).map(|t| t.count).sum()
+	}
+
+	fn publisher_count(sqlite: &Sqlite, publisher: &str) -> u32 {
+		let publishers = sqlite.get_counts::<PublisherCount>(false, true, 0, 1000, Some(publisher.to_string())).expect("Publisher counts failed");
+		publishers.payload.iter().filter(|p| p.publisher == publisher).map(|p| p.count).sum()
+	}
+
+	#[test]
+	#[serial]
+	fn incremental_rescan() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+		fs::create_dir("target/images")?;
+		copy_library()?;
+		scan_library(false);
+		let db_dir = &"target/index".to_string();
+
+		//nothing has changed on disk, so a rescan should leave the index and counts exactly as they were
+		scan_library(true);
+		let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+		let result = reader.search("*", 0, 10, &SearchOptions::default()).expect("Search failed");
+		assert!(result.count == 8);
+		//ids are stable across scans
+		assert!(reader.get_book(-5302641238507735522).is_some());
+		let sqlite = Sqlite::new(db_dir).unwrap();
+		assert!(author_count(&sqlite, "Dickens") == 2);
+
+		let hard_times = reader.search("title:\"hard times\"", 0, 1, &SearchOptions::default()).expect("Search failed").payload.remove(0);
+		let dorian = reader.search("title:dorian", 0, 1, &SearchOptions::default()).expect("Search failed").payload.remove(0);
+		//the tags as counted, which splits up subjects like "Fiction; Gothic"
+		let mut dorian_tags = HashMap::new();
+		dorian.add_tags(&mut dorian_tags);
+		let tags_before: Vec<(String, u32, u32)> = dorian_tags.iter().map(|(tag, n)| (tag.clone(), *n, tag_count(&sqlite, tag))).collect();
+		let publisher_before = dorian.publisher.as_ref().map(|publisher| publisher_count(&sqlite, publisher));
+		drop(reader);
+		drop(sqlite);
+
+		//a touched book is read again but still counted once, and a deleted one is gone from the index and the counts
+		let modified = time::SystemTime::now() + time::Duration::from_secs(60);
+		fs::File::options().write(true).open("target/library/charles-dickens_hard-times.epub")?.set_modified(modified)?;
+		fs::remove_file("target/library/oscar-wilde_the-picture-of-dorian-gray.epub")?;
+		scan_library(true);
+
+		let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+		let result = reader.search("*", 0, 10, &SearchOptions::default()).expect("Search failed");
+		assert!(result.count == 7);
+		assert!(reader.get_book(hard_times.id).is_some());
+		assert!(reader.get_book(dorian.id).is_none());
+		assert!(reader.search("title:dorian", 0, 10, &SearchOptions::default()).expect("Search failed").count == 0);
+
+		let sqlite = Sqlite::new(db_dir).unwrap();
+		assert!(author_count(&sqlite, "Dickens") == 2);
+		assert!(author_count(&sqlite, "Oscar Wilde") == 0);
+		for (tag, n, before) in tags_before {
+			assert!(tag_count(&sqlite, &tag) == before - n);
+		}
+		if let (Some(publisher), Some(before)) = (&dorian.publisher, publisher_before) {
+			assert!(publisher_count(&sqlite, publisher) == before - 1);
+		}
+		drop(reader);
+		drop(sqlite);
+
+		//a changed book that can't be read any more, say one still being copied, keeps what was indexed from it
+		fs::write("target/library/victor-hugo_les-miserables.epub", "not an epub")?;
+		scan_library(true);
+		let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+		assert!(reader.search("*", 0, 10, &SearchOptions::default()).expect("Search failed").count == 7);
+		let sqlite = Sqlite::new(db_dir).unwrap();
+		assert!(author_count(&sqlite, "Victor Hugo") == 1);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn watch_changes() -> Result<(), Error> {
+		tidy();
+		let _dirs_cleanup = DirsCleanup;
+		fs::create_dir("target/images")?;
+		copy_library()?;
+		fs::remove_file("target/library/victor-hugo_les-miserables.epub")?;
+		scan_library(false);
+
+		//as watch mode does between batches of changes, without waiting on the file system to tell us about them
+		let db_dir = &"target/index".to_string();
+		let mut writer = ttvy::TantivyWriter::open(db_dir).unwrap();
+		let sqlite = Sqlite::new(db_dir).unwrap();
+		let mut indexed = writer.indexed_files().expect("Indexed files failed");
+		let mut registry = sqlite.load_registry().expect("Registry failed");
+		registry.adopt(&indexed);
+		let library = fs::canonicalize("target/library")?;
+		let mut changed = |file: &str| {
+			let paths = HashSet::from([library.join(file)]);
+			scanner::apply_changes(paths, &scan_options(false, false), &mut writer, &sqlite, &mut indexed, &mut registry).expect("Changes failed")
+		};
+		let book_count = || {
+			let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+			reader.search("*", 0, 10, &SearchOptions::default()).expect("Search failed").count
+		};
+		assert!(book_count() == 7);
+
+		fs::copy("test/library/victor-hugo_les-miserables.epub", "target/library/victor-hugo_les-miserables.epub")?;
+		assert!(changed("victor-hugo_les-miserables.epub") == (0, 1));
+		assert!(book_count() == 8);
+		assert!(author_count(&sqlite, "Victor Hugo") == 1);
+
+		let modified = time::SystemTime::now() + time::Duration::from_secs(60);
+		fs::File::options().write(true).open("target/library/charles-dickens_hard-times.epub")?.set_modified(modified)?;
+		assert!(changed("charles-dickens_hard-times.epub") == (1, 1));
+		assert!(book_count() == 8);
+		assert!(author_count(&sqlite, "Dickens") == 2);
+		//nothing more has happened to it since
+		assert!(changed("charles-dickens_hard-times.epub") == (0, 0));
+
+		fs::remove_file("target/library/oscar-wilde_the-picture-of-dorian-gray.epub")?;
+		assert!(changed("oscar-wilde_the-picture-of-dorian-gray.epub") == (1, 0));
+		assert!(book_count() == 7);
+		assert!(author_count(&sqlite, "Oscar Wilde") == 0);
+
+		Ok(())
+	}
+
+	//rewrite an epub with its metadata changed, as someone fixing it would
+	fn edit_opf(file: &str, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+		let mut archive = zip::ZipArchive::new(fs::File::open(file)?)?;
+		let edited = format!("{}.edited", file);
+		let mut writer = zip::ZipWriter::new(fs::File::create(&edited)?);
+		for i in 0..archive.len() {
+			let entry = archive.by_index(i)?;
+			if entry.name().ends_with(".opf") {
+				let name = entry.name().to_string();
+				let opf = std::io::read_to_string(entry)?;
+				writer.start_file(name, zip::write::SimpleFileOptions::default())?;
+				std::io::Write::write_all(&mut writer, opf.replace(from, to).as_bytes())?;
+			} else {
+				drop(entry);
+				writer.raw_copy_file(archive.by_index_raw(i)?)?;
+			}
+		}
+		writer.finish()?;
+		fs::rename(edited, file)?;
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn ids_survive_changes() -> Result<(), Error> {
+		tidy();
+		let _dirs_cleanup = DirsCleanup;
+		fs::create_dir("target/images")?;
+		copy_library()?;
+		scan_library(false);
+		let db_dir = &"target/index".to_string();
+		let find = |query: &str| {
+			let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+			reader.search(query, 0, 1, &SearchOptions::default()).expect("Search failed").payload.pop()
+		};
+		let hard_times = find("title:\"hard times\"").unwrap();
+		let dorian = find("title:dorian").unwrap();
+
+		//as the server looks books up, following aliases to where a book has gone
+		let get_book = |id: i64| {
+			let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+			let sqlite = Sqlite::new(db_dir).unwrap();
+			reader.get_book(id).or_else(|| sqlite.get_current_id(id).and_then(|current_id| reader.get_book(current_id)))
+		};
+
+		//one book is renamed and another has its metadata fixed, and links to either keep working
+		fs::rename("target/library/oscar-wilde_the-picture-of-dorian-gray.epub", "target/library/dorian-gray.epub")?;
+		edit_opf("target/library/charles-dickens_hard-times.epub", ">Hard Times</dc:title>", ">Coketown</dc:title>").expect("Edit failed");
+		scan_library(true);
+		assert!(get_book(dorian.id).unwrap().file.ends_with("dorian-gray.epub"));
+		assert!(get_book(hard_times.id).unwrap().title.as_deref() == Some("Coketown"));
+
+		//and they still do once the index is rebuilt from scratch
+		fs::remove_dir_all(db_dir)?;
+		scan_library(false);
+		assert!(find("*").is_some());
+		assert!(get_book(dorian.id).unwrap().file.ends_with("dorian-gray.epub"));
+		assert!(get_book(hard_times.id).unwrap().title.as_deref() == Some("Coketown"));
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn series() -> Result<(), Error> {
+		tidy();
+		let _dirs_cleanup = DirsCleanup;
+		fs::create_dir("target/images")?;
+		copy_library()?;
+		let in_series = |file: &str, index: Option<&str>| {
+			let mut meta = "<meta name=\"calibre:series\" content=\"Victorian Novels\"/>".to_string();
+			if let Some(index) = index {
+				meta.push_str(&format!("<meta name=\"calibre:series_index\" content=\"{}\"/>", index));
+			}
+			edit_opf(&format!("target/library/{}", file), "</metadata>", &format!("{}</metadata>", meta)).expect("Edit failed");
+		};
+		in_series("charles-dickens_bleak-house.epub", Some("2"));
+		in_series("charles-dickens_hard-times.epub", Some("1.5"));
+		in_series("oscar-wilde_the-picture-of-dorian-gray.epub", None);
+		scan_library(false);
+		let db_dir = &"target/index".to_string();
+
+		//in reading order, with anything of unknown place at the end
+		let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+		let books = reader.series_books("Victorian Novels").expect("Series failed");
+		assert!(books.count == 3);
+		let titles: Vec<&str> = books.payload.iter().map(|book| book.title.as_deref().unwrap()).collect();
+		assert!(titles == vec!["Hard Times", "Bleak House", "The Picture of Dorian Gray"]);
+		assert!(books.payload[0].series_index == Some(1.5));
+
+		let sqlite = Sqlite::new(db_dir).unwrap();
+		let series = sqlite.get_counts::<SeriesCount>(false, true, 0, 100, None).expect("Series counts failed");
+		assert!(series.payload.len() == 1);
+		assert!(series.payload[0].series == "Victorian Novels" && series.payload[0].count == 3);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn books_by_isbn() -> Result<(), Error> {
+		tidy();
+		let _dirs_cleanup = DirsCleanup;
+		fs::create_dir("target/images")?;
+		copy_library()?;
+		edit_opf(
+			"target/library/charles-dickens_bleak-house.epub",
+			"</metadata>",
+			"<dc:identifier id=\"isbn\">urn:isbn:0-14-143972-6</dc:identifier></metadata>",
+		)
+		.expect("Edit failed");
+		scan_library(false);
+
+		//as /api/book/by-identifier/isbn/... looks them up, and either form of the ISBN finds the book
+		let reader = ttvy::TantivyReader::new("target/index".to_string()).expect("Reader failed");
+		for isbn in ["0141439726", "978-0-14-143972-3"] {
+			let identifier = identifier::classify(isbn, Some(identifier::ISBN)).unwrap();
+			let result = reader.books_by_identifier(&identifier).expect("Lookup failed");
+			assert!(result.count == 1);
+			assert!(result.payload[0].title.as_deref() == Some("Bleak House"));
+			assert!(result.payload[0].isbn.as_deref() == Some("9780141439723"));
+		}
+		let identifier = identifier::classify("9780141439563", Some(identifier::ISBN)).unwrap();
+		assert!(reader.books_by_identifier(&identifier).expect("Lookup failed").count == 0);
+
+		Ok(())
+	}
+
+	//the test library's epubs have had their text taken out to keep them small, so give one a chapter back
+	fn add_chapter(file: &str, name: &str, xhtml: &str) -> Result<(), Box<dyn std::error::Error>> {
+		let mut writer = zip::ZipWriter::new_append(fs::File::options().read(true).write(true).open(file)?)?;
+		writer.start_file(name, zip::write::SimpleFileOptions::default())?;
+		std::io::Write::write_all(&mut writer, xhtml.as_bytes())?;
+		writer.finish()?;
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn fulltext_search() -> Result<(), Error> {
+		tidy();
+		let _dirs_cleanup = DirsCleanup;
+		fs::create_dir("target/images")?;
+		copy_library()?;
+		add_chapter(
+			"target/library/charles-dickens_hard-times.epub",
+			"epub/text/chapter-1-1.xhtml",
+			"<html><body><h2>The One Thing Needful</h2><p>“Now, what I want is, Facts. Teach these boys and girls nothing but Facts.”</p></body></html>",
+		)
+		.expect("Edit failed");
+		let db_dir = &"target/index".to_string();
+		let mut writer = ttvy::TantivyWriter::new(db_dir).unwrap();
+		let sql_writer = Sqlite::new(db_dir).unwrap();
+		scanner::scan_dirs(&["target/library".to_string()].to_vec(), &scan_options(false, true), &mut writer, &sql_writer).expect("Scan failed");
+
+		let reader = ttvy::TantivyReader::new(db_dir.to_string()).expect("Reader failed");
+		let highlight = SearchOptions {
+			highlight: true,
+			..Default::default()
+		};
+		let result = reader.search("content:\"nothing but facts\"", 0, 10, &highlight).expect("Search failed");
+		assert!(result.count == 1);
+		assert!(result.payload[0].title.as_deref() == Some("Hard Times"));
+		//the text isn't kept in the index, and snippets only come from descriptions
+		assert!(result.payload[0].content.is_none());
+		assert!(result.payload[0].snippet.is_none());
+		assert!(reader.search("content:coketown", 0, 10, &highlight).expect("Search failed").count == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn creators_and_contributors() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let contributor = |name: &str, role: Option<&str>| Contributor {
+			name: name.to_string(),
+			role: role.map(|role| role.to_string()),
+		};
+		let reader = index_books(&[vec![
+			BookMetadata {
+				creator: Some("Anton Chekhov".to_string()),
+				creators: Some(vec!["Anton Chekhov".to_string()]),
+				contributors: Some(vec![contributor("Constance Garnett", Some("trl")), contributor("Edward Garnett", None)]),
+				..book(1, "The Lady with the Dog")
+			},
+			BookMetadata {
+				creator: Some("Terry Pratchett".to_string()),
+				creators: Some(vec!["Terry Pratchett".to_string(), "Neil Gaiman".to_string()]),
+				..book(2, "Good Omens")
+			},
+		]]);
+		let search = |query: &str| reader.search(query, 0, 10, &SearchOptions::default()).expect("Search failed");
+
+		//every author is searched, not just the first
+		let result = search("creator:gaiman");
+		assert!(result.count == 1);
+		assert!(result.to_json().contains("\"creators\":[\"Terry Pratchett\",\"Neil Gaiman\"],"));
+
+		//contributors keep their roles, in order, even when some have none
+		let result = search("contributor:garnett");
+		assert!(result.count == 1);
+		assert!(result.to_json().contains("\"creators\":[\"Anton Chekhov\"],"));
+		assert!(result.to_json().contains("{\"name\":\"Constance Garnett\",\"role\":\"trl\"}"));
+		assert!(result.payload[0].contributors == Some(vec![contributor("Constance Garnett", Some("trl")), contributor("Edward Garnett", None)]));
+		assert!(search("contributor:pratchett").count == 0);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn publication_dates() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let published = |id: i64, pubdate: OffsetDateTime| BookMetadata {
+			pubdate: Some(pubdate),
+			..book(id, &format!("Volume {}", id))
+		};
+		let reader = index_books(&[vec![
+			published(1, datetime!(1853-03-01 0:00 UTC)),
+			published(2, datetime!(1854-04-01 0:00 UTC)),
+			published(3, datetime!(2014-05-25 9:30 UTC)),
+			published(4, datetime!(2014-05-25 23:59 UTC)),
+			published(5, datetime!(2014-05-26 0:00 UTC)),
+			published(6, datetime!(2018-12-31 0:00 UTC)),
+			published(7, datetime!(2019-01-15 4:52:30 UTC)),
+			book(8, "Undated"),
+		]]);
+		let count = |query: &str| reader.search(query, 0, 10, &SearchOptions::default()).expect("Search failed").count;
+
+		//years cover the whole of each year, anything more precise is a range of dates
+		assert!(count("pubdate:[2018 TO 2019]") == 2);
+		assert!(count("pubdate:1853") == 1);
+		assert!(count("pubdate:[* TO 1900]") == 2);
+		assert!(count("pubdate:[2014-05-25T00:00:00Z TO 2014-05-26T00:00:00Z}") == 2);
+		assert!(count("pubdate:[2014-05-25T00:00:00Z TO 2014-05-26T00:00:00Z]") == 3);
+
+		//browsed by decade, then by year within one
+		let decades = reader.year_counts(None).expect("Year counts failed");
+		let counts: Vec<(&str, usize)> = decades.categories.iter().map(|cat| (cat.prefix.as_str(), cat.count)).collect();
+		assert!(counts == vec![("1850", 2), ("2010", 5)]);
+		let years = reader.year_counts(Some(2010)).expect("Year counts failed");
+		let counts: Vec<(&str, usize)> = years.categories.iter().map(|cat| (cat.prefix.as_str(), cat.count)).collect();
+		assert!(counts == vec![("2014", 3), ("2018", 1), ("2019", 1)]);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn highlighted_snippets() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let reader = index_books(&[vec![
+			BookMetadata {
+				description: Some("<p>A distinguished <em>amateur</em> scientist goes to sea.</p>".to_string()),
+				..book(1, "The Voyage of the Beagle")
+			},
+			book(2, "The Amateur Cracksman"),
+		]]);
+		let highlight = SearchOptions {
+			highlight: true,
+			..Default::default()
+		};
+
+		//from the description, with its markup taken out first
+		let result = reader.search("amateur", 0, 10, &highlight).expect("Search failed");
+		assert!(result.count == 2);
+		let snippet = |id: i64| result.payload.iter().find(|book| book.id == id).unwrap().snippet.clone();
+		assert!(snippet(1).unwrap().starts_with("A distinguished <b>amateur</b> scientist"));
+		//a book matched on something else has no snippet
+		assert!(snippet(2).is_none());
+		//and there are none unless asked for
+		let result = reader.search("amateur", 0, 10, &SearchOptions::default()).expect("Search failed");
+		assert!(result.payload.iter().all(|book| book.snippet.is_none()));
+		assert!(!result.to_json().contains("\"snippet\""));
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn languages() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let in_language = |id: i64, language: &str| BookMetadata {
+			language: Some(language.to_string()),
+			..book(id, &format!("Volume {}", id))
+		};
+		let reader = index_books(&[vec![in_language(1, "en-GB"), in_language(2, "en-GB"), in_language(3, "en-US"), in_language(4, "fr"), book(5, "Volume 5")]]);
+		let count = |query: &str| reader.search(query, 0, 10, &SearchOptions::default()).expect("Search failed").count;
+
+		//a language takes in all its regions, however the tag is written
+		assert!(count("lang:en") == 3);
+		assert!(count("lang:en_gb") == 2);
+		assert!(count("lang:EN-us") == 1);
+		assert!(count("* -lang:en_gb") == 3);
+		assert!(count("lang:fr") == 1);
+		let options = SearchOptions {
+			filters: vec![(FacetKind::Language, "en".to_string())],
+			..Default::default()
+		};
+		assert!(reader.search("*", 0, 10, &options).expect("Search failed").count == 3);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn fuzzy_search() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let by = |id: i64, title: &str, author: &str| BookMetadata {
+			creator: Some(author.to_string()),
+			creators: Some(vec![author.to_string()]),
+			..book(id, title)
+		};
+		let reader = index_books(&[vec![
+			by(1, "On the Origin of Species", "Charles Darwin"),
+			by(2, "Bleak House", "Charles Dickens"),
+			by(3, "A Christmas Carol", "Charles Dickins"),
+			by(4, "The Cat", "Anon"),
+		]]);
+		let fuzzy = SearchOptions {
+			fuzzy: true,
+			..Default::default()
+		};
+		let ids = |query: &str, options: &SearchOptions| -> Vec<i64> {
+			reader.search(query, 0, 10, options).expect("Search failed").payload.iter().map(|book| book.id).collect()
+		};
+
+		//a misspelling only matches when fuzzy
+		assert!(ids("darwn", &SearchOptions::default()).is_empty());
+		assert!(ids("darwn", &fuzzy) == vec![1]);
+		//and exact matches still rank first
+		assert!(ids("dickens", &fuzzy) == vec![2, 3]);
+		//short words are left alone, as a typo in one is too easily another word
+		assert!(ids("bat", &fuzzy).is_empty());
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn similar_books() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let by = |id: i64, title: &str, author: &str| BookMetadata {
+			creator: Some(author.to_string()),
+			creators: Some(vec![author.to_string()]),
+			..book(id, title)
+		};
+		let reader = index_books(&[vec![
+			by(1, "Hard Times", "Charles Dickens"),
+			by(2, "Bleak House", "Charles Dickens"),
+			//another copy of the first, found again under a different id
+			by(3, "Hard Times", "Charles Dickens"),
+			by(4, "The Picture of Dorian Gray", "Oscar Wilde"),
+			by(5, "On the Origin of Species", "Charles Darwin"),
+			by(6, "The Cherry Orchard", "Anton Chekhov"),
+		]]);
+
+		//the other Dickens is like this one, but a book is never like itself or a copy of itself
+		let similar = reader.similar_books(1, 10).expect("Similar books failed").unwrap();
+		let ids: Vec<i64> = similar.payload.iter().map(|book| book.id).collect();
+		assert!(ids == vec![2]);
+		assert!(similar.query.as_deref() == Some("similar:1"));
+		assert!(reader.similar_books(42, 10).expect("Similar books failed").is_none());
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn stemming_by_language() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		//a mostly English library with one French book in it
+		let mut books: Vec<BookMetadata> = (1..=10)
+			.map(|id| BookMetadata {
+				description: Some("<p>A distinguished amateur scientist goes to sea.</p>".to_string()),
+				language: Some("en".to_string()),
+				..book(id, &format!("Volume {}", id))
+			})
+			.collect();
+		books.push(BookMetadata {
+			description: Some("<p>Les oiseaux chantaient dans les arbres.</p>".to_string()),
+			language: Some("fr".to_string()),
+			..book(11, "Le Rossignol")
+		});
+		let reader = index_books(&[books]);
+
+		let count = |query: &str, filters: Vec<(FacetKind, String)>| {
+			let options = SearchOptions {
+				filters,
+				..Default::default()
+			};
+			reader.search(query, 0, 20, &options).expect("Search failed").count
+		};
+		//accents are folded away, in the query as well as the book
+		assert!(count("amatéur", vec![]) == 10);
+		assert!(count("rossignól", vec![]) == 1);
+		//English is most of the library, so is always stemmed
+		assert!(count("distinguish", vec![]) == 10);
+		//French only when asked for, by a filter or in the query
+		assert!(count("chant", vec![]) == 0);
+		assert!(count("chant", vec![(FacetKind::Language, "fr".to_string())]) == 1);
+		assert!(count("chant lang:fr", vec![]) == 1);
+		assert!(count("chantaient", vec![]) == 1);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn suggestions() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		//copies of a book can be in different segments
+		let dickens = |id: i64, title: &str| BookMetadata {
+			creator: Some("Charles Dickens".to_string()),
+			..book(id, title)
+		};
+		let reader = index_books(&[vec![dickens(1, "Bleak House"), dickens(2, "Hard Times")], vec![dickens(3, "Bleak House")]]);
+		let sqlite = Sqlite::new(&"target/index".to_string()).unwrap();
+		sqlite.make_db().expect("Make db failed");
+		sqlite.write_counts::<AuthorCount>(HashMap::from([("Charles Dickens".to_string(), 3)])).expect("Write counts failed");
+
+		let titles = reader.title_counts().expect("Title counts failed");
+		assert!(titles == HashMap::from([("Bleak House".to_string(), 2), ("Hard Times".to_string(), 1)]));
+
+		//as /api/suggest gives them
+		let suggester = Suggester::build(&sqlite, &reader).expect("Suggester failed");
+		let suggestions = suggester.suggest("h", 10).payload;
+		let texts: Vec<&str> = suggestions.iter().map(|suggestion| suggestion.text.as_str()).collect();
+		assert!(texts == vec!["Bleak House", "Hard Times"]);
+		assert!(suggestions[0].kind == suggest::TITLE && suggestions[0].count == 2);
+		let suggestions = suggester.suggest("dick", 10).payload;
+		assert!(suggestions.len() == 1 && suggestions[0].kind == suggest::AUTHOR && suggestions[0].count == 3);
+
+		//a book indexed since changes the reader's generation, which is how the server knows to build them again
+		let mut writer = ttvy::TantivyWriter::open(&"target/index".to_string()).unwrap();
+		writer.write_epubs(&vec![dickens(4, "Little Dorrit")]).expect("Write failed");
+		writer.commit().expect("Commit failed");
+		drop(writer);
+		for _ in 0..50 {
+			if reader.generation() != suggester.generation {
+				break;
+			}
+			thread::sleep(time::Duration::from_millis(100));
+		}
+		assert!(reader.generation() != suggester.generation);
+		let suggester = Suggester::build(&sqlite, &reader).expect("Suggester failed");
+		assert!(suggester.suggest("dorrit", 10).payload[0].text == "Little Dorrit");
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn facet_counts() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let reader = index_books(&[vec![
+			BookMetadata {
+				creators: Some(vec!["Charles Dickens".to_string()]),
+				subject: Some(vec!["Fiction; London".to_string()]),
+				publisher: Some("Bradbury & Evans".to_string()),
+				pubdate: Some(datetime!(1853-03-01 0:00 UTC)),
+				..book(1, "Bleak House")
+			},
+			BookMetadata {
+				creators: Some(vec!["Charles Dickens".to_string()]),
+				subject: Some(vec!["Fiction".to_string()]),
+				publisher: Some("Bradbury & Evans".to_string()),
+				pubdate: Some(datetime!(1854-04-01 0:00 UTC)),
+				..book(2, "Hard Times")
+			},
+			BookMetadata {
+				creators: Some(vec!["Oscar Wilde".to_string()]),
+				subject: Some(vec!["Fiction".to_string()]),
+				publisher: Some("Ward Lock".to_string()),
+				pubdate: Some(datetime!(1890-07-01 0:00 UTC)),
+				..book(3, "The Picture of Dorian Gray")
+			},
+		]]);
+		let search = |filters: Vec<(FacetKind, String)>| {
+			let options = SearchOptions {
+				facets: true,
+				filters,
+				..Default::default()
+			};
+			reader.search("*", 0, 10, &options).expect("Search failed")
+		};
+		let values = |result: &SearchResult<BookMetadata>, facet: &str| -> Vec<(String, u64)> {
+			let group = result.facets.iter().flatten().find(|group| group.facet == facet).unwrap();
+			group.values.iter().map(|value| (value.value.clone(), value.count)).collect()
+		};
+
+		//the commonest of each first
+		let result = search(vec![]);
+		assert!(values(&result, "tag") == vec![("fiction".to_string(), 3), ("london".to_string(), 1)]);
+		assert!(values(&result, "author") == vec![("Charles Dickens".to_string(), 2), ("Oscar Wilde".to_string(), 1)]);
+		assert!(values(&result, "publisher") == vec![("Bradbury & Evans".to_string(), 2), ("Ward Lock".to_string(), 1)]);
+		assert!(values(&result, "decade") == vec![("1850".to_string(), 2), ("1890".to_string(), 1)]);
+
+		//and counted again within whatever they drill down to
+		let result = search(vec![(FacetKind::Author, "Charles Dickens".to_string())]);
+		assert!(result.count == 2);
+		assert!(result.to_json().contains("{\"facet\":\"author\",\"values\":[{\"value\":\"Charles Dickens\",\"count\":2}]}"));
+		assert!(values(&result, "decade") == vec![("1850".to_string(), 2)]);
+		let result = search(vec![(FacetKind::Decade, "1890".to_string())]);
+		assert!(result.count == 1 && result.payload[0].id == 3);
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn tag_filter() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let book = BookMetadata {
+			subject: Some(vec!["Научная Фантастика; Science Fiction".to_string()]),
+			..book(1, "Пикник на обочине")
+		};
+		let mut tags = HashMap::new();
+		book.add_tags(&mut tags);
+		let reader = index_books(&[vec![book]]);
+
+		//tags are counted and filtered on the same, whatever case or script they are given in
+		assert!(tags.contains_key("научная фантастика") && tags.contains_key("science fiction"));
+		for tag in ["научная фантастика", "НАУЧНАЯ ФАНТАСТИКА", " Science Fiction "] {
+			let options = SearchOptions {
+				filters: vec![(FacetKind::Tag, tag.to_string())],
+				..Default::default()
+			};
+			assert!(reader.search("*", 0, 10, &options).expect("Search failed").count == 1);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn cursor_paging() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		//over more than one segment, with some books lacking what they are sorted by
+		let dated = |id: i64, year: i32| BookMetadata {
+			pubdate: Some(datetime!(2000-01-01 0:00 UTC).replace_year(year).unwrap()),
+			..book(id, &format!("Volume {}", id))
+		};
+		let reader = index_books(&[
+			vec![dated(1, 1853), dated(2, 1854), dated(3, 1890), book(4, "Volume 4")],
+			vec![dated(5, 1853), book(6, "Volume 6"), dated(7, 2001), BookMetadata { title: None, ..book(8, "") }],
+		]);
+
+		//paging through with cursors visits every book exactly once, whatever the order
+		for sort in [Sort::default(), Sort::parse("title", None).unwrap(), Sort::parse("pubdate", None).unwrap()] {
+			let mut paged = SearchOptions {
+				sort,
+				..Default::default()
+			};
+			let mut pages = vec![];
+			loop {
+				let result = reader.search("*", 0, 3, &paged).expect("Search failed");
+				assert!(result.count == 8);
+				assert!(result.previous_cursor.is_none() == pages.is_empty());
+				pages.push(result.payload.iter().map(|book| book.id).collect::<Vec<i64>>());
+				match &result.cursor {
+					Some(cursor) => paged.after = Some(Cursor::parse(cursor).expect("Cursor should parse")),
+					None => break,
+				}
+			}
+			let mut ids = pages.concat();
+			ids.sort();
+			assert!(ids == (1..=8).collect::<Vec<i64>>());
+
+			//and going back from the last page gives the same pages in turn, until the first has nothing before it
+			let last = reader.search("*", 0, 3, &paged).expect("Search failed");
+			let mut previous_cursor = last.previous_cursor;
+			paged.after = None;
+			for page in pages.iter().rev().skip(1) {
+				paged.before = Some(Cursor::parse(&previous_cursor.expect("Should have a previous page")).expect("Cursor should parse"));
+				let result = reader.search("*", 0, 3, &paged).expect("Search failed");
+				assert!(&result.payload.iter().map(|book| book.id).collect::<Vec<i64>>() == page);
+				assert!(result.cursor.is_some());
+				previous_cursor = result.previous_cursor;
+			}
+			assert!(previous_cursor.is_none());
+		}
+		assert!(Cursor::parse("not a cursor").is_none());
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn paging_copies() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		//copies of the same book share its id, and rank the same whatever the order
+		let copies: Vec<BookMetadata> = (0..7)
+			.map(|copy| BookMetadata {
+				file: format!("/books/copy-{}/bleak-house.epub", copy),
+				..book(42, "Bleak House")
+			})
+			.collect();
+		let reader = index_books(&[copies]);
+
+		for sort in [Sort::default(), Sort::parse("title", None).unwrap()] {
+			let mut paged = SearchOptions {
+				sort,
+				..Default::default()
+			};
+			let mut files = HashSet::new();
+			loop {
+				let result = reader.search("bleak", 0, 2, &paged).expect("Search failed");
+				for book in result.payload {
+					assert!(files.insert(book.file));
+				}
+				match &result.cursor {
+					Some(cursor) => paged.after = Some(Cursor::parse(cursor).expect("Cursor should parse")),
+					None => break,
+				}
+			}
+			assert!(files.len() == 7);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn format_facets() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let in_format = |id: i64, format: BookFormat| BookMetadata {
+			mime: format.mime().to_string(),
+			..book(id, "Bleak House")
+		};
+		let reader = index_books(&[vec![in_format(1, BookFormat::Epub), in_format(2, BookFormat::Pdf), in_format(3, BookFormat::Epub)]]);
+		let by_format = |format: Option<&str>| SearchOptions {
+			facets: true,
+			filters: format.map(|format| (FacetKind::Format, format.to_string())).into_iter().collect(),
+			..Default::default()
+		};
+
+		//counted along with the other facets, commonest first
+		let result = reader.search("bleak", 0, 10, &by_format(None)).expect("Search failed");
+		assert!(result.to_json().contains("{\"facet\":\"format\",\"values\":[{\"value\":\"epub\",\"count\":2},{\"value\":\"pdf\",\"count\":1}]}"));
+
+		//and filtered on by extension, which has to be one we know
+		let result = reader.search("*", 0, 10, &by_format(Some("epub"))).expect("Search failed");
+		assert!(result.count == 2);
+		assert!(result.to_json().contains("{\"facet\":\"format\",\"values\":[{\"value\":\"epub\",\"count\":2}]}"));
+		assert!(reader.search("*", 0, 10, &by_format(Some("pdf"))).expect("Search failed").count == 1);
+		assert!(reader.search("*", 0, 10, &by_format(Some("cbz"))).expect("Search failed").count == 0);
+		assert!(reader.search("*", 0, 10, &by_format(Some("doc"))).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn sort_orders() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		let reader = index_books(&[vec![
+			BookMetadata {
+				creator: Some("Charles Dickens".to_string()),
+				filesize: 300,
+				..book(1, "Bleak House")
+			},
+			BookMetadata {
+				creator: Some("Anton Chekhov".to_string()),
+				filesize: 100,
+				..book(2, "The Cherry Orchard")
+			},
+			BookMetadata {
+				creator: Some("Oscar Wilde".to_string()),
+				filesize: 200,
+				..book(3, "An Ideal Husband")
+			},
+		]]);
+		let ids = |field: &str, order: Option<&str>| -> Vec<i64> {
+			let options = SearchOptions {
+				sort: Sort::parse(field, order).unwrap(),
+				..Default::default()
+			};
+			reader.search("*", 0, 10, &options).expect("Search failed").payload.iter().map(|book| book.id).collect()
+		};
+		//biggest first unless asked otherwise, and authors by surname
+		assert!(ids("filesize", None) == vec![1, 3, 2]);
+		assert!(ids("filesize", Some("asc")) == vec![2, 3, 1]);
+		assert!(ids("author", Some("asc")) == vec![2, 1, 3]);
+		//titles without the article
+		assert!(ids("title", Some("asc")) == vec![1, 2, 3]);
+		assert!(Sort::parse("colour", None).is_none());
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn sort_with_missing_fields() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		//each commit is a segment of its own, and the second has no book with a title, author or publication date
+		let dated = BookMetadata {
+			creator: Some("Charles Dickens".to_string()),
+			pubdate: Some(datetime!(1853-01-01 0:00 UTC)),
+			..book(1, "Bleak House")
+		};
+		let undated = BookMetadata {
+			title: None,
+			..book(2, "")
+		};
+		let reader = index_books(&[vec![dated], vec![undated]]);
+		for (field, order) in [("pubdate", "desc"), ("pubdate", "asc"), ("title", "asc"), ("author", "desc")] {
+			let options = SearchOptions {
+				sort: Sort::parse(field, Some(order)).unwrap(),
+				..Default::default()
+			};
+			let result = reader.search("*", 0, 10, &options).expect("Sorting by a field some segments lack failed");
+			assert!(result.count == 2);
+			//books without the field go last, whichever the order
+			assert!(result.payload.first().unwrap().id == 1);
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	#[serial]
+	fn older_index() -> Result<(), Error> {
+		let _dirs_cleanup = clean_dirs();
+
+		//an index from before fields were added can't be updated or read, only rebuilt
+		fs::create_dir("target/index")?;
+		let mut schema_builder = tantivy::schema::SchemaBuilder::default();
+		schema_builder.add_i64_field("id", tantivy::schema::STORED);
+		tantivy::Index::create_in_dir("target/index", schema_builder.build()).expect("Index failed");
+		let opened = ttvy::TantivyWriter::open(&"target/index".to_string());
+		assert!(opened.is_err_and(|e| e.to_string().contains("rebuild without --incremental")));
+		assert!(ttvy::TantivyReader::new("target/index".to_string()).is_err());
+
+		Ok(())
+	}
 }
@@ -1,47 +1,124 @@
 use std::error::Error;
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::io;
 use std::path::Path;
 use std::process;
+use std::sync::OnceLock;
 
-use tantivy::collector::{Collector, Count, SegmentCollector, TopDocs};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use regex::{Captures, Regex};
+
+use tantivy::columnar::{Column, MonotonicallyMappableToU64};
+use tantivy::collector::{Collector, Count, DocSetCollector, FacetCollector, FacetCounts, SegmentCollector, TopDocs};
 use tantivy::directory::MmapDirectory;
-use tantivy::query::TermQuery;
+use tantivy::query::{AllQuery, BooleanQuery, BoostQuery, FuzzyTermQuery, MoreLikeThisQuery, Occur, Query, RangeQuery, TermQuery};
+use tantivy::snippet::SnippetGenerator;
 use tantivy::schema::*;
 use tantivy::store::StoreReader;
+use tantivy::tokenizer::{AsciiFoldingFilter, Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer};
+use tantivy::DocAddress;
 use tantivy::DocId;
 use tantivy::IndexWriter;
 use tantivy::Score;
 use tantivy::SegmentOrdinal;
 use tantivy::SegmentReader;
+use tantivy::Searcher;
 use tantivy::{Index, IndexReader, ReloadPolicy};
 
-use crate::error::StoreError;
-use crate::search_result::{Category, CategorySearchResult, SearchResult};
+use crate::error::{ClientError, StoreError};
+use crate::fulltext::strip_markup;
+use crate::scanner::BookFormat;
+use crate::language::normalise_language;
+use crate::normalise_tag;
+use crate::search_result::{Category, CategorySearchResult, FacetGroup, FacetValue, SearchResult};
 use crate::BookMetadata;
 use crate::BookWriter;
+use crate::Contributor;
+use crate::IndexedFile;
 use ammonia::{Builder, UrlRelative};
 use futures::executor;
 use tantivy::query::QueryParser;
-use time::OffsetDateTime;
+use time::{Date, Month, OffsetDateTime};
+
+//Too common to say anything about what a book is like
+const STOP_WORDS: [&str; 24] = [
+	"the", "and", "for", "with", "that", "this", "from", "his", "her", "was", "are", "which", "their", "has", "have", "not",
+	"but", "its", "who", "all", "one", "book", "edition", "ebook",
+];
+
+//How many of the commonest values of each facet to count
+const FACET_LIMIT: usize = 10;
+
+//Lower cased with accents folded away, so "Cechov" finds "Čechov"
+const FOLDED: &str = "folded";
+
+//The snowball stemmers tantivy has, by primary BCP 47 language subtag. Each gets its own description field.
+const STEMMED_LANGUAGES: [(&str, Language); 20] = [
+	("ar", Language::Arabic),
+	("da", Language::Danish),
+	("de", Language::German),
+	("el", Language::Greek),
+	("en", Language::English),
+	("es", Language::Spanish),
+	("fi", Language::Finnish),
+	("fr", Language::French),
+	("hu", Language::Hungarian),
+	("it", Language::Italian),
+	("nb", Language::Norwegian),
+	("nl", Language::Dutch),
+	("nn", Language::Norwegian),
+	("no", Language::Norwegian),
+	("pt", Language::Portuguese),
+	("ro", Language::Romanian),
+	("ru", Language::Russian),
+	("sv", Language::Swedish),
+	("ta", Language::Tamil),
+	("tr", Language::Turkish),
+];
 
 pub struct TantivyWriter<'a> {
 	index_writer: std::sync::RwLock<IndexWriter>,
 	id: Field,
 	title: Field,
+	title_sort: Field,
 	description: Field,
+	description_stemmed: HashMap<&'static str, Field>,
 	publisher: Field,
 	creator: Field,
+	creator_sort: Field,
+	creator_facet: Field,
+	publisher_facet: Field,
 	file: Field,
+	file_hash: Field,
 	filesize: Field,
 	modtime: Field,
 	pubdate: Field,
+	pubyear: Field,
 	moddate: Field,
 	cover_mime: Field,
 	tags: Field,
+	mime: Field,
+	format_facet: Field,
+	pages: Field,
+	series: Field,
+	series_facet: Field,
+	series_index: Field,
+	issue: Field,
+	contributor: Field,
+	contributor_role: Field,
+	isbn: Field,
+	identifiers: Field,
+	language: Field,
+	lang: Field,
+	content: Field,
 	sanitiser: Builder<'a>,
 }
 
@@ -53,25 +130,6 @@ impl<'a> TantivyWriter<'a> {
 		}
 		fs::create_dir(&dir)?;
 
-		//build our schema
-		let mut schema_builder = SchemaBuilder::default();
-		//let id_options = IntOptions::default().set_stored().set_indexed();
-		let id = schema_builder.add_i64_field("id", NumericOptions::default().set_stored().set_indexed());
-		let title = schema_builder.add_text_field("title", TEXT | STORED);
-		let description = schema_builder.add_text_field("description", TEXT | STORED);
-		let publisher = schema_builder.add_text_field("publisher", TEXT | STORED);
-		let creator = schema_builder.add_text_field("creator", TEXT | STORED);
-		//subject
-		let file = schema_builder.add_text_field("file", STRING | STORED);
-		let filesize = schema_builder.add_i64_field("filesize", NumericOptions::default().set_stored().set_indexed());
-		//let modtime = schema_builder.add_i64_field("modtime", IntOptions::default().set_stored().set_indexed().set_fast(Cardinality::SingleValue));
-		let modtime = schema_builder.add_date_field("modtime", FAST | STORED);
-		let pubdate = schema_builder.add_text_field("pubdate", TEXT | STORED);
-		let moddate = schema_builder.add_text_field("moddate", TEXT | STORED);
-		//let moddate = schema_builder.add_date_field("moddate", STORED | INDEXED);
-		let cover_mime = schema_builder.add_text_field("cover_mime", TEXT | STORED);
-		let tags = schema_builder.add_facet_field("tags", STORED | INDEXED);
-		let schema = schema_builder.build();
 		let path_dir = dir.clone();
 		let path = Path::new(&path_dir);
 		//let mmap_dir = MmapDirectory::open(path)?;
@@ -84,7 +142,86 @@ impl<'a> TantivyWriter<'a> {
 			docstore_blocksize: 16384,
 			docstore_compress_dedicated_thread: true,
 		};*/
-		let index = Index::create_in_dir(path, schema.clone())?;
+		let index = Index::create_in_dir(path, TantivyWriter::build_schema())?;
+		TantivyWriter::from_index(index)
+	}
+
+	//Open an index created by an earlier run so it can be updated in place
+	pub fn open(dir: &String) -> Result<TantivyWriter<'a>, tantivy::TantivyError> {
+		if !Path::new(&dir).exists() {
+			println!("Error: No index found in directory {}. Run without --incremental to create one.", &dir);
+			process::exit(3);
+		}
+
+		let index = Index::open_in_dir(Path::new(&dir))?;
+		if index.schema() != TantivyWriter::build_schema() {
+			return Err(tantivy::TantivyError::SchemaError(format!(
+				"the index in {} was built by an older version, rebuild without --incremental",
+				dir
+			)));
+		}
+		TantivyWriter::from_index(index)
+	}
+
+	fn build_schema() -> Schema {
+		let mut schema_builder = SchemaBuilder::default();
+		//let id_options = IntOptions::default().set_stored().set_indexed();
+		schema_builder.add_i64_field("id", NumericOptions::default().set_stored().set_indexed());
+		//the whole title as a fast field too, for suggestions
+		schema_builder.add_text_field("title", folded_text().set_fast(Some("raw")) | STORED);
+		//unstemmed, for exact phrases - the stemmed copies are indexed in the field for the book's language
+		schema_builder.add_text_field("description", folded_text() | STORED);
+		for (code, _) in STEMMED_LANGUAGES {
+			schema_builder.add_text_field(&format!("description_{}", code), stemmed_text(code));
+		}
+		schema_builder.add_text_field("publisher", folded_text() | STORED);
+		//one value per author
+		schema_builder.add_text_field("creator", folded_text() | STORED);
+		//subject
+		schema_builder.add_text_field("file", STRING | STORED);
+		//to break ties when paging - copies of a book share its id, but never a file
+		schema_builder.add_u64_field("file_hash", NumericOptions::default().set_fast());
+		schema_builder.add_i64_field("filesize", NumericOptions::default().set_stored().set_indexed().set_fast());
+		//the start of the title and of the first author's name, for sorting
+		schema_builder.add_u64_field("title_sort", FAST);
+		schema_builder.add_u64_field("creator_sort", FAST);
+		//every author and the publisher again as facets, to count and drill down by, as with series
+		schema_builder.add_facet_field("creator_facet", INDEXED);
+		schema_builder.add_facet_field("publisher_facet", INDEXED);
+		//let modtime = schema_builder.add_i64_field("modtime", IntOptions::default().set_stored().set_indexed().set_fast(Cardinality::SingleValue));
+		schema_builder.add_date_field("modtime", FAST | STORED);
+		schema_builder.add_date_field("pubdate", INDEXED | STORED | FAST);
+		//tantivy dates can't reach back before 1678, so the year is kept separately for browsing and year range queries
+		schema_builder.add_i64_field("pubyear", INDEXED | STORED | FAST);
+		schema_builder.add_date_field("moddate", INDEXED | STORED | FAST);
+		schema_builder.add_text_field("cover_mime", TEXT | STORED);
+		schema_builder.add_facet_field("tags", STORED | INDEXED);
+		schema_builder.add_text_field("mime", STRING | STORED);
+		//the format by its extension, to count alongside the other facets
+		schema_builder.add_facet_field("format_facet", FacetOptions::default());
+		schema_builder.add_i64_field("pages", NumericOptions::default().set_stored().set_indexed());
+		schema_builder.add_text_field("series", folded_text() | STORED);
+		//the series name as a whole, for listing exactly one series rather than everything sharing a word with it
+		schema_builder.add_facet_field("series_facet", FacetOptions::default());
+		schema_builder.add_f64_field("series_index", STORED | FAST);
+		schema_builder.add_text_field("issue", STRING | STORED);
+		//stored side by side, so the nth role is the nth contributor's
+		schema_builder.add_text_field("contributor", folded_text() | STORED);
+		schema_builder.add_text_field("contributor_role", STRING | STORED);
+		schema_builder.add_text_field("isbn", STRING | STORED);
+		schema_builder.add_text_field("identifiers", STRING | STORED);
+		schema_builder.add_text_field("language", STRING | STORED);
+		//the language again as a facet, so "lang:en" finds en-GB and en-US too
+		schema_builder.add_facet_field("lang", FacetOptions::default());
+		//only populated with --fulltext, and deliberately not one of the default search fields
+		//only searched, never stored - a book's text would more than double the size of the index
+		schema_builder.add_text_field("content", folded_text());
+		schema_builder.build()
+	}
+
+	fn from_index(index: Index) -> Result<TantivyWriter<'a>, tantivy::TantivyError> {
+		register_tokenizers(&index);
+		let schema = index.schema();
 		let writer = index.writer(50_000_000)?;
 
 		let mut b = Builder::default();
@@ -117,18 +254,42 @@ impl<'a> TantivyWriter<'a> {
 
 		Ok(TantivyWriter {
 			index_writer: std::sync::RwLock::new(writer),
-			id,
-			title,
-			description,
-			publisher,
-			creator,
-			file,
-			filesize,
-			modtime,
-			pubdate,
-			moddate,
-			cover_mime,
-			tags,
+			id: schema.get_field("id")?,
+			title: schema.get_field("title")?,
+			title_sort: schema.get_field("title_sort")?,
+			description: schema.get_field("description")?,
+			description_stemmed: STEMMED_LANGUAGES
+				.iter()
+				.map(|(code, _)| Ok((*code, schema.get_field(&format!("description_{}", code))?)))
+				.collect::<Result<HashMap<&'static str, Field>, tantivy::TantivyError>>()?,
+			publisher: schema.get_field("publisher")?,
+			creator: schema.get_field("creator")?,
+			creator_sort: schema.get_field("creator_sort")?,
+			creator_facet: schema.get_field("creator_facet")?,
+			publisher_facet: schema.get_field("publisher_facet")?,
+			file: schema.get_field("file")?,
+			file_hash: schema.get_field("file_hash")?,
+			filesize: schema.get_field("filesize")?,
+			modtime: schema.get_field("modtime")?,
+			pubdate: schema.get_field("pubdate")?,
+			pubyear: schema.get_field("pubyear")?,
+			moddate: schema.get_field("moddate")?,
+			cover_mime: schema.get_field("cover_mime")?,
+			tags: schema.get_field("tags")?,
+			mime: schema.get_field("mime")?,
+			format_facet: schema.get_field("format_facet")?,
+			pages: schema.get_field("pages")?,
+			series: schema.get_field("series")?,
+			series_facet: schema.get_field("series_facet")?,
+			series_index: schema.get_field("series_index")?,
+			issue: schema.get_field("issue")?,
+			contributor: schema.get_field("contributor")?,
+			contributor_role: schema.get_field("contributor_role")?,
+			isbn: schema.get_field("isbn")?,
+			identifiers: schema.get_field("identifiers")?,
+			language: schema.get_field("language")?,
+			lang: schema.get_field("lang")?,
+			content: schema.get_field("content")?,
 			sanitiser: b,
 		})
 	}
@@ -141,6 +302,12 @@ impl<'a> BookWriter for TantivyWriter<'a> {
 			let mut ttdoc = TantivyDocument::default();
 			ttdoc.add_i64(self.id, bm.id);
 			ttdoc.add_text(self.title, bm.title.as_ref().unwrap_or(&empty_str));
+			if let Some(title) = &bm.title {
+				ttdoc.add_u64(self.title_sort, title_sort_key(title));
+			}
+			if let Some(creator) = &bm.creator {
+				ttdoc.add_u64(self.creator_sort, creator_sort_key(creator));
+			}
 			ttdoc.add_text(
 				self.description,
 				self.sanitiser
@@ -149,14 +316,71 @@ impl<'a> BookWriter for TantivyWriter<'a> {
 					.as_str(),
 			);
 			ttdoc.add_text(self.publisher, bm.publisher.as_ref().unwrap_or(&empty_str));
-			ttdoc.add_text(self.creator, bm.creator.as_ref().unwrap_or(&empty_str));
+			if let Some(publisher) = bm.publisher.as_ref().filter(|publisher| !publisher.is_empty()) {
+				ttdoc.add_facet(self.publisher_facet, Facet::from_path(vec![publisher]));
+			}
+			match &bm.creators {
+				Some(creators) if !creators.is_empty() => {
+					for creator in creators {
+						ttdoc.add_text(self.creator, creator);
+						ttdoc.add_facet(self.creator_facet, Facet::from_path(vec![creator]));
+					}
+				}
+				_ => ttdoc.add_text(self.creator, &empty_str),
+			}
 			ttdoc.add_text(self.file, &bm.file);
+			ttdoc.add_u64(self.file_hash, file_hash(&bm.file));
 			ttdoc.add_i64(self.filesize, bm.filesize);
 
 			ttdoc.add_date(self.modtime, tantivy::DateTime::from_utc(bm.modtime));
-			ttdoc.add_text(self.pubdate, bm.pubdate.as_ref().unwrap_or(&empty_str));
-			ttdoc.add_text(self.moddate, &bm.moddate.as_ref().unwrap_or(&empty_str));
+			if let Some(pubdate) = bm.pubdate {
+				ttdoc.add_i64(self.pubyear, pubdate.year() as i64);
+				if let Some(pubdate) = to_tantivy_date(pubdate) {
+					ttdoc.add_date(self.pubdate, pubdate);
+				}
+			}
+			if let Some(moddate) = bm.moddate.and_then(to_tantivy_date) {
+				ttdoc.add_date(self.moddate, moddate);
+			}
 			ttdoc.add_text(self.cover_mime, &bm.cover_mime.as_ref().unwrap_or(&empty_str));
+			ttdoc.add_text(self.mime, &bm.mime);
+			if let Some(format) = BookFormat::from_mime(&bm.mime) {
+				ttdoc.add_facet(self.format_facet, Facet::from_path(vec![format.extension()]));
+			}
+			if let Some(pages) = bm.pages {
+				ttdoc.add_i64(self.pages, pages);
+			}
+			if let Some(series) = &bm.series {
+				ttdoc.add_text(self.series, series);
+				ttdoc.add_facet(self.series_facet, Facet::from_path(vec![series]));
+			}
+			if let Some(series_index) = bm.series_index {
+				ttdoc.add_f64(self.series_index, series_index);
+			}
+			if let Some(issue) = &bm.issue {
+				ttdoc.add_text(self.issue, issue);
+			}
+			for contributor in bm.contributors.iter().flatten() {
+				ttdoc.add_text(self.contributor, &contributor.name);
+				ttdoc.add_text(self.contributor_role, contributor.role.as_ref().unwrap_or(&empty_str));
+			}
+			if let Some(isbn) = &bm.isbn {
+				ttdoc.add_text(self.isbn, isbn);
+			}
+			for identifier in bm.identifiers.iter().flatten() {
+				ttdoc.add_text(self.identifiers, identifier);
+			}
+			if let Some(language) = &bm.language {
+				ttdoc.add_text(self.language, language);
+				ttdoc.add_facet(self.lang, Facet::from_path(language.split('-')));
+				let primary = language.split('-').next().unwrap_or(language);
+				if let (Some(field), Some(description)) = (self.description_stemmed.get(primary), &bm.description) {
+					ttdoc.add_text(*field, strip_markup(description));
+				}
+			}
+			if let Some(content) = &bm.content {
+				ttdoc.add_text(self.content, content);
+			}
 
 			if bm.subject.is_some() {
 				let mut tagsmap = HashMap::new();
@@ -179,6 +403,49 @@ impl<'a> BookWriter for TantivyWriter<'a> {
 		Ok(())
 	}
 
+	fn indexed_files(&self) -> Result<HashMap<String, IndexedFile>, Box<dyn Error>> {
+		let searcher = self.index_writer.read().unwrap().index().reader()?.searcher();
+		let mut files = HashMap::new();
+
+		for segment_reader in searcher.segment_readers() {
+			let store_reader = segment_reader.get_store_reader(100)?;
+			for doc_id in segment_reader.doc_ids_alive() {
+				let doc: TantivyDocument = store_reader.get(doc_id)?;
+				let file = match doc.get_first(self.file).and_then(|val| val.as_str()) {
+					Some(file) => file.to_string(),
+					None => continue,
+				};
+				files.insert(
+					file,
+					IndexedFile {
+						id: get_doc_i64("id", &doc, searcher.schema()),
+						filesize: get_doc_i64("filesize", &doc, searcher.schema()),
+						modtime: get_doc_datetime("modtime", &doc, searcher.schema()),
+					},
+				);
+			}
+		}
+
+		Ok(files)
+	}
+
+	fn remove_epubs(&mut self, ids: &[i64]) -> Result<Vec<BookMetadata>, Box<dyn Error>> {
+		let searcher = self.index_writer.read().unwrap().index().reader()?.searcher();
+		let mut removed = Vec::new();
+
+		for id in ids {
+			let id_term = Term::from_field_i64(self.id, *id);
+			let term_query = TermQuery::new(id_term.clone(), IndexRecordOption::Basic);
+			for (_, doc_addr) in searcher.search(&term_query, &TopDocs::with_limit(1))? {
+				let doc: TantivyDocument = searcher.doc(doc_addr)?;
+				removed.push(doc_to_bm(&doc, searcher.schema()));
+			}
+			self.index_writer.read().unwrap().delete_term(id_term);
+		}
+
+		Ok(removed)
+	}
+
 	fn commit(&mut self) -> Result<(), Box<dyn Error>> {
 		match self.index_writer.write().unwrap().commit() {
 			Ok(_) => Ok(()),
@@ -195,11 +462,142 @@ impl<'a> BookWriter for TantivyWriter<'a> {
 	}
 }
 
+//How a search should be run, beyond the query itself
+#[derive(Debug, Default, Clone)]
+pub struct SearchOptions {
+	pub highlight: bool,
+	pub fuzzy: bool, //tolerate typos, "Dostoievski" finds "Dostoevsky"
+	pub sort: Sort,
+	pub facets: bool, //count tags, authors etc among everything found
+	pub filters: Vec<(FacetKind, String)>, //only books with all of these, eg (Tag, "horror")
+	pub after: Option<Cursor>, //the next page, from where the last one left off
+	pub before: Option<Cursor>, //or the previous page, from where the last one began
+}
+
+//Where a page of results ended, or began - the sort key and file hash of its last or first book. Opaque to clients, who hand it
+//back for the next or previous page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+	key: u64,
+	file_hash: u64,
+}
+
+impl Cursor {
+	pub fn parse(cursor: &str) -> Option<Cursor> {
+		let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+		let (key, file_hash) = decoded.split_once(':')?;
+		Some(Cursor {
+			key: key.parse().ok()?,
+			file_hash: file_hash.parse().ok()?,
+		})
+	}
+}
+
+impl fmt::Display for Cursor {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", URL_SAFE_NO_PAD.encode(format!("{}:{}", self.key, self.file_hash)))
+	}
+}
+
+//What search results can be counted by and narrowed down to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FacetKind {
+	Tag,
+	Author,
+	Publisher,
+	Language,
+	Decade,
+	Format,
+}
+
+pub const FACET_KINDS: [FacetKind; 6] = [
+	FacetKind::Tag,
+	FacetKind::Author,
+	FacetKind::Publisher,
+	FacetKind::Language,
+	FacetKind::Decade,
+	FacetKind::Format,
+];
+
+impl FacetKind {
+	//also the name of the request parameter to filter by it
+	pub fn name(&self) -> &'static str {
+		match self {
+			FacetKind::Tag => "tag",
+			FacetKind::Author => "author",
+			FacetKind::Publisher => "publisher",
+			FacetKind::Language => "lang",
+			FacetKind::Decade => "decade",
+			FacetKind::Format => "format",
+		}
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SortField {
+	#[default]
+	Relevance,
+	Title,
+	Author,
+	Modtime,
+	Pubdate,
+	Filesize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Sort {
+	pub field: SortField,
+	pub descending: bool,
+}
+
+impl Sort {
+	//eg "title", or "pubdate" with order "asc". Without an order, names go A-Z and everything else biggest or newest first.
+	pub fn parse(field: &str, order: Option<&str>) -> Option<Sort> {
+		let field = match field.to_ascii_lowercase().as_str() {
+			"relevance" => SortField::Relevance,
+			"title" => SortField::Title,
+			"author" | "creator" => SortField::Author,
+			"modtime" | "newest" => SortField::Modtime,
+			"pubdate" => SortField::Pubdate,
+			"filesize" | "size" => SortField::Filesize,
+			_ => return None,
+		};
+		let descending = match order.map(|order| order.to_ascii_lowercase()).as_deref() {
+			Some("asc") => false,
+			Some("desc") => true,
+			Some(_) => return None,
+			None => !matches!(field, SortField::Title | SortField::Author),
+		};
+		Some(Sort { field, descending })
+	}
+
+	//The fast field to order by, or none for relevance
+	fn fast_field(&self) -> Option<&'static str> {
+		match self.field {
+			SortField::Relevance => None,
+			SortField::Title => Some("title_sort"),
+			SortField::Author => Some("creator_sort"),
+			SortField::Modtime => Some("modtime"),
+			SortField::Pubdate => Some("pubdate"),
+			SortField::Filesize => Some("filesize"),
+		}
+	}
+}
+
 pub struct TantivyReader {
 	reader: IndexReader,
 	query_parser: QueryParser,
+	//for queries in a known language, which only look in descriptions stemmed for it
+	language_parsers: HashMap<&'static str, QueryParser>,
 	id_field: Field,
+	description_field: Field,
+	series_facet_field: Field,
+	identifiers_field: Field,
 	tags_field: Field,
+	creator_facet_field: Field,
+	publisher_facet_field: Field,
+	lang_field: Field,
+	mime_field: Field,
 }
 
 impl TantivyReader {
@@ -207,23 +605,57 @@ impl TantivyReader {
 		let path = Path::new(&index);
 		let mmap_dir = MmapDirectory::open(path)?;
 		let index = Index::open(mmap_dir)?;
+		//books would come back with fields missing, or not at all
+		if index.schema() != TantivyWriter::build_schema() {
+			return Err(StoreError::InitError(
+				"The index was built by an older version, rebuild it with index (without --incremental)".to_string(),
+			));
+		}
+		register_tokenizers(&index);
 		let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommitWithDelay).try_into()?;
 		let searcher = reader.searcher();
 		let schema = searcher.schema();
-		let mut query_parser = QueryParser::for_index(
-			&index,
-			vec![
-				index.schema().get_field("creator").unwrap(),
-				index.schema().get_field("title").unwrap(),
-				index.schema().get_field("description").unwrap(),
-			],
-		);
-		query_parser.set_conjunction_by_default();
+		let unstemmed_fields = vec![
+			index.schema().get_field("creator").unwrap(),
+			index.schema().get_field("title").unwrap(),
+			index.schema().get_field("description").unwrap(),
+			index.schema().get_field("series").unwrap(),
+		];
+		let stemmed_field = |code: &str| TantivyReader::get_field(schema, &format!("description_{}", code));
+		let query_parser_for = |fields: Vec<Field>| {
+			let mut query_parser = QueryParser::for_index(&index, fields);
+			query_parser.set_conjunction_by_default();
+			query_parser
+		};
+
+		//a word stemmed for one language can match an unrelated one stemmed for another, so only the languages most of
+		//the library is in are searched when the query doesn't say
+		let mut default_fields = unstemmed_fields.clone();
+		for code in main_languages(&searcher)? {
+			if STEMMED_LANGUAGES.iter().any(|(stemmed, _)| *stemmed == code) {
+				default_fields.push(stemmed_field(&code)?);
+			}
+		}
+		let query_parser = query_parser_for(default_fields);
+		let mut language_parsers = HashMap::new();
+		for (code, _) in STEMMED_LANGUAGES {
+			let mut fields = unstemmed_fields.clone();
+			fields.push(stemmed_field(code)?);
+			language_parsers.insert(code, query_parser_for(fields));
+		}
 		Ok(TantivyReader {
 			reader,
 			query_parser,
+			language_parsers,
 			id_field: TantivyReader::get_field(schema, "id")?,
+			description_field: TantivyReader::get_field(schema, "description")?,
+			series_facet_field: TantivyReader::get_field(schema, "series_facet")?,
+			identifiers_field: TantivyReader::get_field(schema, "identifiers")?,
 			tags_field: TantivyReader::get_field(schema, "tags")?,
+			creator_facet_field: TantivyReader::get_field(schema, "creator_facet")?,
+			publisher_facet_field: TantivyReader::get_field(schema, "publisher_facet")?,
+			lang_field: TantivyReader::get_field(schema, "lang")?,
+			mime_field: TantivyReader::get_field(schema, "mime")?,
 		})
 	}
 
@@ -241,35 +673,341 @@ impl TantivyReader {
 	}
 
 	//    /api/search
-	pub fn search(&self, query: &str, start: usize, limit: usize) -> Result<SearchResult<BookMetadata>, StoreError> {
+	pub fn search(&self, query: &str, start: usize, limit: usize, options: &SearchOptions) -> Result<SearchResult<BookMetadata>, StoreError> {
 		let searcher = &self.reader.searcher();
 
-		let tquery = &self.query_parser.parse_query(query)?;
+		let rewritten = rewrite_query(query);
+		let language = options
+			.filters
+			.iter()
+			.find(|(kind, _)| *kind == FacetKind::Language)
+			.and_then(|(_, language)| normalise_language(language))
+			.or_else(|| query_language(&rewritten));
+		let query_parser = language
+			.and_then(|language| self.language_parsers.get(language.split('-').next().unwrap_or_default()))
+			.unwrap_or(&self.query_parser);
+		let mut tquery = query_parser.parse_query(&rewritten)?;
+		if options.fuzzy {
+			tquery = fuzzify(tquery, searcher.schema());
+		}
+		if !options.filters.is_empty() {
+			let mut clauses = vec![(Occur::Must, tquery)];
+			for (kind, value) in &options.filters {
+				clauses.push((Occur::Must, self.filter_query(*kind, value)?));
+			}
+			tquery = Box::new(BooleanQuery::new(clauses));
+		}
+		let tquery = &tquery;
+
+		//Books are ranked by a sort key - the relevance score, or a fast field, mapped onto u64s - then by file, so that there is
+		//always an exact place to resume from. Anything up to and including the cursor ranks as None and is dropped, which
+		//makes every page cost the same however deep it is. Going back, the order is turned around until the page is found.
+		let after = options.after.map(|cursor| (cursor.key, Reverse(cursor.file_hash)));
+		let before = options.before.map(|cursor| (cursor.key, Reverse(cursor.file_hash)));
+		let skip = if after.is_some() || before.is_some() { 0 } else { start };
+		//one more than the page, to tell whether there is anything beyond it
+		let top_collector = TopDocs::with_limit(skip + limit + 1);
+		let (docs, count) = match options.sort.fast_field() {
+			None => searcher.search(
+				tquery,
+				&(
+					top_collector.tweak_score(move |segment_reader: &SegmentReader| {
+						let file_hashes = segment_reader.fast_fields().u64("file_hash").ok();
+						move |doc: DocId, score: Score| rank((score as f64).to_u64(), doc_file_hash(&file_hashes, doc), after, before)
+					}),
+					Count,
+				),
+			)?,
+			Some(field) => {
+				let descending = options.sort.descending;
+				searcher.search(
+					tquery,
+					&(
+						top_collector.custom_score(move |segment_reader: &SegmentReader| {
+							let file_hashes = segment_reader.fast_fields().u64("file_hash").ok();
+							let values = segment_reader.fast_fields().u64_lenient(field).ok().flatten().map(|(column, _)| column);
+							move |doc: DocId| {
+								//books without the field go last, whichever the order
+								let key = match values.as_ref().and_then(|values| values.first(doc)) {
+									Some(value) if descending => value,
+									Some(value) => u64::MAX - value,
+									None => 0,
+								};
+								rank(key, doc_file_hash(&file_hashes, doc), after, before)
+							}
+						}),
+						Count,
+					),
+				)?
+			}
+		};
+		let mut ranked: Vec<((u64, Reverse<u64>), DocAddress)> = docs
+			.into_iter()
+			.skip(skip)
+			.filter_map(|(rank, doc_addr)| rank.map(|rank| (rank, doc_addr)))
+			.collect();
+		let beyond = ranked.len() > limit;
+		ranked.truncate(limit);
+		if before.is_some() {
+			ranked = ranked.into_iter().rev().map(|(rank, doc_addr)| (turned(rank), doc_addr)).collect();
+		}
 
-		let top_collector = TopDocs::with_limit(start + limit);
-		let count_collector = Count;
-		let docs = searcher.search(tquery, &(top_collector, count_collector))?;
-		let count = docs.1;
+		//a page found going on has more after it if there was one beyond it, and has more before it if it followed any;
+		//one found going back the other way round
+		let (more_after, more_before) = match before {
+			Some(_) => (true, beyond),
+			None => (beyond, after.is_some() || start > 0),
+		};
+		let cursor_at = |ranked: Option<&((u64, Reverse<u64>), DocAddress)>| {
+			ranked.map(|((key, Reverse(file_hash)), _)| {
+				Cursor {
+					key: *key,
+					file_hash: *file_hash,
+				}
+				.to_string()
+			})
+		};
+		let cursor = cursor_at(ranked.last()).filter(|_| more_after);
+		let previous_cursor = cursor_at(ranked.first()).filter(|_| more_before);
+
+		//snippets only come from descriptions - a book's text isn't stored, and reading it again for each book on the page
+		//would mean unzipping as many books as the page shows
+		let snippet_generator = if options.highlight {
+			Some(SnippetGenerator::create(searcher, tquery.as_ref(), self.description_field)?)
+		} else {
+			None
+		};
 
 		let mut books = Vec::new(); //0 {}[]
 
-		for doc_addr in docs.0.iter().skip(start) {
-			let retrieved = match searcher.doc(doc_addr.1) {
+		for (_, doc_addr) in ranked {
+			let retrieved = match searcher.doc(doc_addr) {
 				Ok(doc) => doc,
 				Err(_) => continue,
 			};
 
-			books.push(self.to_bm(&retrieved, &searcher.schema()));
+			let mut bm = doc_to_bm(&retrieved, searcher.schema());
+			if let Some(snippet_generator) = &snippet_generator {
+				bm.snippet = get_snippet(snippet_generator, &bm);
+			}
+			books.push(bm);
 		}
 
+		let facets = if options.facets { Some(self.facet_counts(searcher, tquery.as_ref())?) } else { None };
+
 		Ok(SearchResult {
 			count,
 			start,
 			query: Some(query.to_string()),
 			payload: books,
+			facets,
+			cursor,
+			previous_cursor,
 		})
 	}
 
+	//Narrow a search down to books with the given tag, author etc
+	fn filter_query(&self, kind: FacetKind, value: &str) -> Result<Box<dyn Query>, StoreError> {
+		let facet_query = |field: Field, facet: Facet| -> Box<dyn Query> {
+			Box::new(TermQuery::new(Term::from_facet(field, &facet), IndexRecordOption::Basic))
+		};
+		Ok(match kind {
+			FacetKind::Tag => facet_query(self.tags_field, Facet::from_path(vec![normalise_tag(value)])),
+			FacetKind::Author => facet_query(self.creator_facet_field, Facet::from_path(vec![value.trim()])),
+			FacetKind::Publisher => facet_query(self.publisher_facet_field, Facet::from_path(vec![value.trim()])),
+			FacetKind::Language => match normalise_language(value) {
+				Some(language) => facet_query(self.lang_field, Facet::from_path(language.split('-'))),
+				None => return Err(filter_error(kind, value)),
+			},
+			FacetKind::Decade => match value.trim().parse::<i64>() {
+				Ok(decade) => Box::new(RangeQuery::new_i64("pubyear".to_string(), decade..decade + 10)),
+				Err(_) => return Err(filter_error(kind, value)),
+			},
+			//by extension, eg "epub", or mime type
+			FacetKind::Format => match BookFormat::from_extension(value.trim()).or_else(|| BookFormat::from_mime(value.trim())) {
+				Some(format) => Box::new(TermQuery::new(Term::from_field_text(self.mime_field, format.mime()), IndexRecordOption::Basic)),
+				None => return Err(filter_error(kind, value)),
+			},
+		})
+	}
+
+	//The commonest tags, authors, publishers and languages among the books a query finds, and how many of them are from each decade
+	//and in each format
+	fn facet_counts(&self, searcher: &Searcher, query: &dyn Query) -> Result<Vec<FacetGroup>, StoreError> {
+		let facet_collector = |field: &str| {
+			let mut collector = FacetCollector::for_field(field);
+			collector.add_facet("/");
+			collector
+		};
+		let ((tags, creators, publishers), (languages, formats, years)) = searcher.search(
+			query,
+			&(
+				(facet_collector("tags"), facet_collector("creator_facet"), facet_collector("publisher_facet")),
+				(facet_collector("lang"), facet_collector("format_facet"), YearHistogram::new("pubyear")),
+			),
+		)?;
+
+		let top = |kind: FacetKind, counts: &FacetCounts| FacetGroup {
+			facet: kind.name().to_string(),
+			values: counts
+				.top_k("/", FACET_LIMIT)
+				.into_iter()
+				.map(|(facet, count)| FacetValue {
+					value: facet.to_path().last().unwrap_or(&"").to_string(),
+					count,
+				})
+				.collect(),
+		};
+
+		let mut decades: BTreeMap<i64, u64> = BTreeMap::new();
+		for (year, count) in years {
+			*decades.entry(year.div_euclid(10) * 10).or_insert(0) += count as u64;
+		}
+
+		Ok(vec![
+			top(FacetKind::Tag, &tags),
+			top(FacetKind::Author, &creators),
+			top(FacetKind::Publisher, &publishers),
+			top(FacetKind::Language, &languages),
+			FacetGroup {
+				facet: FacetKind::Decade.name().to_string(),
+				values: decades
+					.into_iter()
+					.map(|(decade, count)| FacetValue {
+						value: decade.to_string(),
+						count,
+					})
+					.collect(),
+			},
+			top(FacetKind::Format, &formats),
+		])
+	}
+
+	//Every book in a series, in reading order. Books with no usable position go last, by title.
+	pub fn series_books(&self, series: &str) -> Result<SearchResult<BookMetadata>, StoreError> {
+		let searcher = &self.reader.searcher();
+		let series_term = Term::from_facet(self.series_facet_field, &Facet::from_path(vec![series]));
+		let term_query = TermQuery::new(series_term, IndexRecordOption::Basic);
+
+		let doc_addrs = searcher.search(&term_query, &DocSetCollector)?;
+		let mut books: Vec<BookMetadata> = doc_addrs
+			.into_iter()
+			.filter_map(|doc_addr| searcher.doc(doc_addr).ok())
+			.map(|doc| doc_to_bm(&doc, searcher.schema()))
+			.collect();
+
+		books.sort_by(|a, b| match (a.series_index, b.series_index) {
+			(Some(a_index), Some(b_index)) => a_index.total_cmp(&b_index),
+			(Some(_), None) => std::cmp::Ordering::Less,
+			(None, Some(_)) => std::cmp::Ordering::Greater,
+			(None, None) => a.title.cmp(&b.title),
+		});
+
+		Ok(SearchResult {
+			count: books.len(),
+			start: 0,
+			query: Some(format!("series:\"{}\"", series)),
+			payload: books,
+			facets: None,
+			cursor: None,
+			previous_cursor: None,
+		})
+	}
+
+	//identifier is an already normalised scheme:value term. Usually one book, but there may be several copies or editions of it.
+	pub fn books_by_identifier(&self, identifier: &str) -> Result<SearchResult<BookMetadata>, StoreError> {
+		let searcher = &self.reader.searcher();
+		let identifier_term = Term::from_field_text(self.identifiers_field, identifier);
+		let term_query = TermQuery::new(identifier_term, IndexRecordOption::Basic);
+
+		let docs = searcher.search(&term_query, &TopDocs::with_limit(100))?;
+		let books: Vec<BookMetadata> = docs
+			.iter()
+			.filter_map(|doc_addr| searcher.doc(doc_addr.1).ok())
+			.map(|doc| doc_to_bm(&doc, searcher.schema()))
+			.collect();
+
+		Ok(SearchResult {
+			count: books.len(),
+			start: 0,
+			query: Some(identifier.to_string()),
+			payload: books,
+			facets: None,
+			cursor: None,
+			previous_cursor: None,
+		})
+	}
+
+	//Books like the given one going by what they're about and who wrote them, or None if there's no such book. Other copies or
+	//editions of the same book are no recommendation, so anything with its title and author or one of its identifiers is left out.
+	pub fn similar_books(&self, id: i64, limit: usize) -> Result<Option<SearchResult<BookMetadata>>, StoreError> {
+		let book = match self.get_book(id) {
+			Some(book) => book,
+			None => return Ok(None),
+		};
+		let searcher = &self.reader.searcher();
+		let schema = searcher.schema();
+
+		let mut doc_fields: Vec<(Field, Vec<OwnedValue>)> = vec![];
+		if let Some(description) = &book.description {
+			doc_fields.push((self.description_field, vec![OwnedValue::Str(strip_markup(description))]));
+		}
+		if let Some(tags) = &book.subject {
+			doc_fields.push((self.tags_field, tags.iter().map(|tag| OwnedValue::Facet(Facet::from_path(vec![tag]))).collect()));
+		}
+		if let Some(creators) = &book.creators {
+			doc_fields.push((
+				TantivyReader::get_field(schema, "creator")?,
+				creators.iter().map(|creator| OwnedValue::Str(creator.to_string())).collect(),
+			));
+		}
+
+		//a term only the book itself has says nothing about any other, and one most books have says little
+		let more_like_this = MoreLikeThisQuery::builder()
+			.with_min_doc_frequency(2)
+			.with_max_doc_frequency((searcher.num_docs() / 2).max(2))
+			.with_min_term_frequency(1)
+			.with_min_word_length(3)
+			.with_max_query_terms(25)
+			.with_boost_factor(1.0)
+			.with_stop_words(STOP_WORDS.iter().map(|word| word.to_string()).collect())
+			.with_document_fields(doc_fields);
+		let query = BooleanQuery::new(vec![
+			(Occur::Must, Box::new(more_like_this) as Box<dyn Query>),
+			(Occur::MustNot, Box::new(TermQuery::new(Term::from_field_i64(self.id_field, id), IndexRecordOption::Basic))),
+		]);
+
+		let same_work = |other: &BookMetadata| {
+			let same_title = book.title.as_ref().map(|title| sort_words(title)) == other.title.as_ref().map(|title| sort_words(title))
+				&& book.creator.as_ref().map(|creator| sort_words(creator)) == other.creator.as_ref().map(|creator| sort_words(creator));
+			let shared_identifier = match (&book.identifiers, &other.identifiers) {
+				(Some(identifiers), Some(others)) => identifiers.iter().any(|identifier| others.contains(identifier)),
+				_ => false,
+			};
+			same_title || shared_identifier
+		};
+
+		//leave room for the copies that will be dropped
+		let docs = searcher.search(&query, &TopDocs::with_limit(limit * 2 + 10))?;
+		let books: Vec<BookMetadata> = docs
+			.iter()
+			.filter_map(|doc_addr| searcher.doc(doc_addr.1).ok())
+			.map(|doc| doc_to_bm(&doc, schema))
+			.filter(|other| !same_work(other))
+			.take(limit)
+			.collect();
+
+		Ok(Some(SearchResult {
+			count: books.len(),
+			start: 0,
+			query: Some(format!("similar:{}", id)),
+			payload: books,
+			facets: None,
+			cursor: None,
+			previous_cursor: None,
+		}))
+	}
+
 	pub fn categorise(&self, field: &str, prefix: &str, query: Option<&str>, floor: usize) -> Result<CategorySearchResult, StoreError> {
 		let searcher = self.reader.searcher();
 		let fld = TantivyReader::get_field(searcher.schema(), field)?;
@@ -308,6 +1046,67 @@ impl TantivyReader {
 		})
 	}
 
+	//Changes whenever the reader picks up a commit, so anything built from the index can tell it is out of date
+	pub fn generation(&self) -> u64 {
+		self.reader.searcher().generation().generation_id()
+	}
+
+	//Every distinct title with how many copies of it there are, read from the fast field rather than the doc store
+	pub fn title_counts(&self) -> Result<HashMap<String, u32>, StoreError> {
+		let searcher = self.reader.searcher();
+		let mut titles = HashMap::new();
+		for segment_reader in searcher.segment_readers() {
+			let column = match segment_reader.fast_fields().str("title")? {
+				Some(column) => column,
+				None => continue,
+			};
+			let mut ord_counts = vec![0u32; column.num_terms()];
+			for doc in 0..segment_reader.max_doc() {
+				if segment_reader.is_deleted(doc) {
+					continue;
+				}
+				for ord in column.ords().values_for_doc(doc) {
+					ord_counts[ord as usize] += 1;
+				}
+			}
+			let mut title = String::new();
+			for (ord, count) in ord_counts.into_iter().enumerate() {
+				if count > 0 && column.ord_to_str(ord as u64, &mut title).map_err(|e| StoreError::DbError(e.to_string()))? {
+					*titles.entry(title.clone()).or_insert(0) += count;
+				}
+			}
+		}
+		Ok(titles)
+	}
+
+	//Books per decade, or per year within a decade
+	pub fn year_counts(&self, decade: Option<i64>) -> Result<CategorySearchResult, StoreError> {
+		let searcher = self.reader.searcher();
+		let years = searcher.search(&AllQuery, &YearHistogram::new("pubyear"))?;
+
+		let mut counts: BTreeMap<i64, usize> = BTreeMap::new();
+		for (year, count) in years {
+			match decade {
+				Some(decade) if year - year.rem_euclid(10) == decade => *counts.entry(year).or_insert(0) += count,
+				Some(_) => (),
+				None => *counts.entry(year - year.rem_euclid(10)).or_insert(0) += count,
+			}
+		}
+
+		let cats_vec: Vec<Category> = counts
+			.into_iter()
+			.map(|(period, count)| Category {
+				prefix: period.to_string(),
+				count,
+			})
+			.collect();
+
+		Ok(CategorySearchResult {
+			count: cats_vec.len(),
+			categories: cats_vec,
+		})
+	}
+
 	pub fn count_by_field(&self, field: &str, prefix: &str) -> Result<CategorySearchResult, StoreError> {
 		let searcher = self.reader.searcher();
 		let prefix = prefix.to_ascii_lowercase();
@@ -358,7 +1157,7 @@ impl TantivyReader {
 				if docs.len() == 1 {
 					match docs.first() {
 						Some(doc_addr) => match searcher.doc(doc_addr.1) {
-							Ok(doc) => Some(self.to_bm(&doc, searcher.schema())),
+							Ok(doc) => Some(doc_to_bm(&doc, searcher.schema())),
 							Err(e) => {
 								println!("Doc disappeared. id:{}, err: {}", id, e);
 								None
@@ -377,63 +1176,351 @@ impl TantivyReader {
 			Err(_) => None,
 		}
 	}
+}
 
-	fn to_bm(&self, doc: &tantivy::TantivyDocument, schema: &Schema) -> BookMetadata {
-		BookMetadata {
-			id: self.get_doc_i64("id", &doc, &schema), //not populated ?
-			title: self.get_doc_str("title", &doc, &schema),
-			description: self.get_doc_str("description", &doc, &schema),
-			publisher: self.get_doc_str("publisher", &doc, &schema),
-			creator: self.get_doc_str("creator", &doc, &schema),
-			subject: self.get_tags("tags", &doc),
-			file: self.get_doc_str("file", &doc, &schema).unwrap(),
-			filesize: self.get_doc_i64("filesize", &doc, &schema),
-			modtime: self.get_doc_datetime("modtime", &doc, &schema),
-			pubdate: self.get_doc_str("pubdate", &doc, &schema),
-			moddate: self.get_doc_str("moddate", &doc, &schema),
-			cover_mime: self.get_doc_str("cover_mime", &doc, &schema),
-		}
+fn doc_to_bm(doc: &tantivy::TantivyDocument, schema: &Schema) -> BookMetadata {
+	BookMetadata {
+		id: get_doc_i64("id", doc, schema), //not populated ?
+		title: get_doc_str("title", doc, schema),
+		description: get_doc_str("description", doc, schema),
+		publisher: get_doc_str("publisher", doc, schema),
+		creator: get_doc_str("creator", doc, schema),
+		creators: get_doc_strs("creator", doc, schema)
+			.map(|creators| creators.into_iter().filter(|c| !c.is_empty()).collect::<Vec<String>>())
+			.filter(|creators| !creators.is_empty()),
+		subject: get_tags("tags", doc, schema),
+		file: get_doc_str("file", doc, schema).unwrap(),
+		filesize: get_doc_i64("filesize", doc, schema),
+		modtime: get_doc_datetime("modtime", doc, schema),
+		pubdate: get_doc_date("pubdate", doc, schema).or_else(|| {
+			doc.get_first(schema.get_field("pubyear").unwrap())
+				.and_then(|val| val.as_i64())
+				.and_then(|year| Date::from_calendar_date(year as i32, Month::January, 1).ok())
+				.map(|date| date.midnight().assume_utc())
+		}),
+		moddate: get_doc_date("moddate", doc, schema),
+		mime: get_doc_str("mime", doc, schema).unwrap_or_else(|| "application/epub+zip".to_string()),
+		pages: doc.get_first(schema.get_field("pages").unwrap()).and_then(|val| val.as_i64()),
+		series: get_doc_str("series", doc, schema),
+		series_index: doc.get_first(schema.get_field("series_index").unwrap()).and_then(|val| val.as_f64()),
+		issue: get_doc_str("issue", doc, schema),
+		contributors: get_contributors(doc, schema),
+		isbn: get_doc_str("isbn", doc, schema),
+		identifiers: get_doc_strs("identifiers", doc, schema),
+		language: get_doc_str("language", doc, schema),
+		cover_mime: get_doc_str("cover_mime", doc, schema),
+		content_hash: None,
+		content: None,
+		snippet: None,
 	}
+}
 
-	//I *know* the fields are present in schema, and I *know* that certain fields eg id are always populated, so just unwrap() here
-	fn get_doc_str(&self, field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> Option<String> {
-		doc.get_first(schema.get_field(field).unwrap()).map(|val| match val.as_str() {
-			Some(t) => t.to_string(),
-			_ => "".to_string(),
-		})
+fn get_snippet(snippet_generator: &SnippetGenerator, bm: &BookMetadata) -> Option<String> {
+	//descriptions are stored as html, which would otherwise end up escaped inside the snippet
+	let description = strip_markup(bm.description.as_ref()?);
+	let snippet = snippet_generator.snippet(&description);
+	if snippet.is_empty() {
+		None
+	} else {
+		Some(snippet.to_html())
+	}
+}
+
+//Higher ranks come first, so a book is after the cursor if it ranks below it. Going back from a cursor only the books before it
+//rank, and the order is turned around so that those just before it come first.
+fn rank(
+	key: u64,
+	file_hash: u64,
+	after: Option<(u64, Reverse<u64>)>,
+	before: Option<(u64, Reverse<u64>)>,
+) -> Option<(u64, Reverse<u64>)> {
+	let rank = (key, Reverse(file_hash));
+	match (after, before) {
+		(Some(after), _) if rank >= after => None,
+		(_, Some(before)) if rank <= before => None,
+		(_, Some(_)) => Some(turned(rank)),
+		_ => Some(rank),
 	}
+}
+
+//The same rank in the opposite order, and back again
+fn turned((key, Reverse(file_hash)): (u64, Reverse<u64>)) -> (u64, Reverse<u64>) {
+	(u64::MAX - key, Reverse(u64::MAX - file_hash))
+}
+
+fn doc_file_hash(file_hashes: &Option<Column<u64>>, doc: DocId) -> u64 {
+	file_hashes.as_ref().and_then(|file_hashes| file_hashes.first(doc)).unwrap_or(0)
+}
 
-	fn get_doc_i64(&self, field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> i64 {
-		doc.get_first(schema.get_field(field).unwrap()).unwrap().as_i64().unwrap()
+fn file_hash(file: &str) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	file.hash(&mut hasher);
+	hasher.finish()
+}
+
+fn filter_error(kind: FacetKind, value: &str) -> StoreError {
+	StoreError::from(ClientError {
+		name: "Filter error".to_string(),
+		msg: format!("\"{}\" is not a valid {}", value, kind.name()),
+	})
+}
+
+//Every term on a tokenized text field also matches anything within a few edits of it. The exact term is kept, and boosted,
+//so that a correctly spelled match always ranks above a near miss. Anything else (phrases, ranges, facets...) is left alone.
+fn fuzzify(query: Box<dyn Query>, schema: &Schema) -> Box<dyn Query> {
+	if let Some(boolean_query) = query.downcast_ref::<BooleanQuery>() {
+		let clauses = boolean_query
+			.clauses()
+			.iter()
+			.map(|(occur, clause)| (*occur, fuzzify(clause.box_clone(), schema)))
+			.collect();
+		return Box::new(BooleanQuery::new(clauses));
 	}
 
-	fn get_doc_datetime(&self, field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> OffsetDateTime {
-		doc.get_first(schema.get_field(field).unwrap())
-			.unwrap()
-			.as_datetime()
-			.unwrap()
-			.into_utc()
+	let term = match query.downcast_ref::<TermQuery>() {
+		Some(term_query) => term_query.term().clone(),
+		None => return query,
+	};
+	let tokenized = match schema.get_field_entry(term.field()).field_type() {
+		FieldType::Str(options) => options.get_indexing_options().map(|indexing| indexing.tokenizer() != "raw").unwrap_or(false),
+		_ => false,
+	};
+	let distance = match term.value().as_str() {
+		Some(text) if tokenized => edit_distance(text),
+		_ => 0,
+	};
+	if distance == 0 {
+		return query;
 	}
 
-	fn get_tags(&self, _field: &str, doc: &tantivy::TantivyDocument) -> Option<Vec<String>> {
-		let vals = doc.get_all(self.tags_field);
+	Box::new(BooleanQuery::new(vec![
+		(Occur::Should, Box::new(BoostQuery::new(query, 2.0)) as Box<dyn Query>),
+		(Occur::Should, Box::new(FuzzyTermQuery::new(term, distance, true))),
+	]))
+}
 
-		let mut tags = Vec::new();
+//Short words are too easily turned into other words, so the longer the word the more typos it may have
+fn edit_distance(text: &str) -> u8 {
+	match text.chars().count() {
+		0..=3 => 0,
+		4..=7 => 1,
+		_ => 2,
+	}
+}
 
-		for v in vals {
-			//println!("{:?}", (v as &Facet));
-			tags.push(v.as_facet().unwrap().encoded_str().to_string());
-			//tags.push(v.as_str().unwrap_or("").to_string());
-		}
+//Ignoring "The" and "A", as any library would
+fn title_sort_key(title: &str) -> u64 {
+	let mut words = sort_words(title);
+	if words.len() > 1 && matches!(words[0].as_str(), "the" | "a" | "an") {
+		words.remove(0);
+	}
+	pack_sort_key(&words.join(" "))
+}
 
-		if tags.len() == 0 {
-			return None;
-		}
+//By surname, so "Charles Dickens" sorts as "dickens charles"
+fn creator_sort_key(creator: &str) -> u64 {
+	let mut words = sort_words(creator);
+	if let Some(surname) = words.pop() {
+		words.insert(0, surname);
+	}
+	pack_sort_key(&words.join(" "))
+}
 
-		Some(tags)
+fn sort_words(text: &str) -> Vec<String> {
+	let mut analyzer = TextAnalyzer::builder(SimpleTokenizer::default())
+		.filter(LowerCaser)
+		.filter(AsciiFoldingFilter)
+		.build();
+	let mut stream = analyzer.token_stream(text);
+	let mut words = vec![];
+	while let Some(token) = stream.next() {
+		words.push(token.text.clone());
+	}
+	words
+}
+
+//The first 12 characters in base 37 (space, 0-9, a-z) just fit in a u64, which is plenty to put books in order
+fn pack_sort_key(text: &str) -> u64 {
+	let mut key: u64 = 0;
+	let mut chars = text.chars().filter(|c| *c == ' ' || c.is_ascii_alphanumeric());
+	for _ in 0..12 {
+		let digit = match chars.next() {
+			Some(c @ '0'..='9') => c as u64 - '0' as u64 + 1,
+			Some(c @ 'a'..='z') => c as u64 - 'a' as u64 + 11,
+			Some(_) => 0,
+			None => 0,
+		};
+		key = key * 37 + digit;
+	}
+	key
+}
+
+//Tokenizers aren't persisted with the index, so both writer and reader have to register them before use
+fn register_tokenizers(index: &Index) {
+	index.tokenizers().register(
+		FOLDED,
+		TextAnalyzer::builder(SimpleTokenizer::default())
+			.filter(RemoveLongFilter::limit(40))
+			.filter(LowerCaser)
+			.filter(AsciiFoldingFilter)
+			.build(),
+	);
+	//stem before folding, the stemmers know their own accents
+	for (code, language) in STEMMED_LANGUAGES {
+		index.tokenizers().register(
+			&stemmed_tokenizer(code),
+			TextAnalyzer::builder(SimpleTokenizer::default())
+				.filter(RemoveLongFilter::limit(40))
+				.filter(LowerCaser)
+				.filter(Stemmer::new(language))
+				.filter(AsciiFoldingFilter)
+				.build(),
+		);
 	}
 }
 
+fn stemmed_tokenizer(code: &str) -> String {
+	format!("stemmed_{}", code)
+}
+
+fn text_options(tokenizer: &str) -> TextOptions {
+	TextOptions::default().set_indexing_options(
+		TextFieldIndexing::default()
+			.set_tokenizer(tokenizer)
+			.set_index_option(IndexRecordOption::WithFreqsAndPositions),
+	)
+}
+
+fn folded_text() -> TextOptions {
+	text_options(FOLDED)
+}
+
+//indexed only - the unstemmed description field is the one stored
+fn stemmed_text(code: &str) -> TextOptions {
+	text_options(&stemmed_tokenizer(code))
+}
+
+//Years are kept in their own field, so "pubdate:[1850 TO 1900]" or "pubdate:1887" have to be pointed at that. Anything
+//more precise is left as an RFC 3339 range on pubdate itself. Languages are facets, so "lang:en_us" becomes "lang:/en/US".
+fn rewrite_query(query: &str) -> String {
+	static YEAR_RANGE: OnceLock<Regex> = OnceLock::new();
+	static YEAR: OnceLock<Regex> = OnceLock::new();
+	static LANG: OnceLock<Regex> = OnceLock::new();
+	let year_range = YEAR_RANGE.get_or_init(|| Regex::new(r"\bpubdate:([\[{])\s*(\d{4}|\*)\s+TO\s+(\d{4}|\*)\s*([\]}])").unwrap());
+	let year = YEAR.get_or_init(|| Regex::new(r"\bpubdate:(\d{4})(\s|\)|$)").unwrap());
+
+	let query = year_range.replace_all(query, "pubyear:${1}${2} TO ${3}${4}");
+	let query = year.replace_all(&query, "pubyear:${1}${2}");
+
+	let lang = LANG.get_or_init(|| Regex::new(r"\blang:([A-Za-z]{2,3}(?:[-_][A-Za-z0-9]+)*)").unwrap());
+	lang.replace_all(&query, |caps: &Captures| match normalise_language(&caps[1]) {
+		Some(language) => format!("lang:/{}", language.replace('-', "/")),
+		None => caps[0].to_string(),
+	})
+	.to_string()
+}
+
+//The language a rewritten query asks for with lang:, if it names just the one
+fn query_language(query: &str) -> Option<String> {
+	static LANG_FACET: OnceLock<Regex> = OnceLock::new();
+	let lang_facet = LANG_FACET.get_or_init(|| Regex::new(r"\blang:/([a-z]{2,3})\b").unwrap());
+	let mut languages = lang_facet.captures_iter(query).map(|caps| caps[1].to_string());
+	match (languages.next(), languages.next()) {
+		(Some(language), None) => Some(language),
+		_ => None,
+	}
+}
+
+//Languages at least a tenth of the library is in, by primary subtag
+fn main_languages(searcher: &Searcher) -> Result<Vec<String>, StoreError> {
+	let mut collector = FacetCollector::for_field("lang");
+	collector.add_facet("/");
+	let (counts, total) = searcher.search(&AllQuery, &(collector, Count))?;
+	Ok(counts
+		.get("/")
+		.filter(|(_, count)| *count as usize * 10 >= total)
+		.filter_map(|(facet, _)| facet.to_path().first().map(|code| code.to_string()))
+		.collect())
+}
+
+//I *know* the fields are present in schema, and I *know* that certain fields eg id are always populated, so just unwrap() here
+fn get_doc_str(field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> Option<String> {
+	doc.get_first(schema.get_field(field).unwrap()).map(|val| match val.as_str() {
+		Some(t) => t.to_string(),
+		_ => "".to_string(),
+	})
+}
+
+fn get_doc_strs(field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> Option<Vec<String>> {
+	let vals: Vec<String> = doc
+		.get_all(schema.get_field(field).unwrap())
+		.filter_map(|val| val.as_str().map(|val| val.to_string()))
+		.collect();
+	if vals.is_empty() {
+		None
+	} else {
+		Some(vals)
+	}
+}
+
+fn get_contributors(doc: &tantivy::TantivyDocument, schema: &Schema) -> Option<Vec<Contributor>> {
+	let names = get_doc_strs("contributor", doc, schema)?;
+	let roles = get_doc_strs("contributor_role", doc, schema).unwrap_or_default();
+	Some(
+		names
+			.into_iter()
+			.enumerate()
+			.map(|(i, name)| Contributor {
+				name,
+				role: roles.get(i).filter(|role| !role.is_empty()).cloned(),
+			})
+			.collect(),
+	)
+}
+
+fn get_doc_i64(field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> i64 {
+	doc.get_first(schema.get_field(field).unwrap()).unwrap().as_i64().unwrap()
+}
+
+fn get_doc_datetime(field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> OffsetDateTime {
+	doc.get_first(schema.get_field(field).unwrap())
+		.unwrap()
+		.as_datetime()
+		.unwrap()
+		.into_utc()
+}
+
+fn get_doc_date(field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> Option<OffsetDateTime> {
+	doc.get_first(schema.get_field(field).unwrap())
+		.and_then(|val| val.as_datetime())
+		.map(|date| date.into_utc())
+}
+
+//tantivy keeps dates as i64 nanoseconds, which silently wraps outside of 1678-2262
+fn to_tantivy_date(date: OffsetDateTime) -> Option<tantivy::DateTime> {
+	i64::try_from(date.unix_timestamp_nanos())
+		.ok()
+		.map(|_| tantivy::DateTime::from_utc(date))
+}
+
+fn get_tags(field: &str, doc: &tantivy::TantivyDocument, schema: &Schema) -> Option<Vec<String>> {
+	let vals = doc.get_all(schema.get_field(field).unwrap());
+
+	let mut tags = Vec::new();
+
+	for v in vals {
+		//println!("{:?}", (v as &Facet));
+		tags.push(v.as_facet().unwrap().encoded_str().to_string());
+		//tags.push(v.as_str().unwrap_or("").to_string());
+	}
+
+	if tags.len() == 0 {
+		return None;
+	}
+
+	Some(tags)
+}
+
 //Reduce the search results to top categories with numbers of each
 pub struct AlphabeticalCategories<'a> {
 	char_position: usize, //1 means first letter, 2 means 2nd letter etc
@@ -524,18 +1611,82 @@ impl SegmentCollector for AlphabeticalCategoriesSegmentCollector {
 		//segmentReader.get_store_reader().get(docId) => slow (returns LZ4 block to decompress!)
 		//If it is a facet - segmentReader.facet_reader() then facet_reader.facet_ords() & facet_from_ords()
 		let document: TantivyDocument = self.store_reader.get(doc).unwrap();
-		let field_text = document.get_first(self.category_field).unwrap().as_str().unwrap();
-		//println!("pos: {} text:{:?}:", self.char_position, &field_text.chars());
-		//not populated - just ignore it
-
-		if field_text.to_ascii_uppercase().starts_with(&self.prefix) {
-			match field_text.chars().nth(self.char_position - 1) {
-				Some(char) => self.fruit.insert(
-					char.to_ascii_uppercase(),
-					self.fruit.get(&char.to_ascii_uppercase()).unwrap_or(&0) + 1,
-				),
-				None => None,
-			};
+		//fields like creator can hold several values, each counts
+		for field_text in document.get_all(self.category_field).filter_map(|val| val.as_str()) {
+			//println!("pos: {} text:{:?}:", self.char_position, &field_text.chars());
+			//not populated - just ignore it
+
+			if field_text.to_ascii_uppercase().starts_with(&self.prefix) {
+				match field_text.chars().nth(self.char_position - 1) {
+					Some(char) => self.fruit.insert(
+						char.to_ascii_uppercase(),
+						self.fruit.get(&char.to_ascii_uppercase()).unwrap_or(&0) + 1,
+					),
+					None => None,
+				};
+			}
+		}
+	}
+
+	fn harvest(self) -> Self::Fruit {
+		self.fruit
+	}
+}
+
+//Count books by year of publication, straight from the pubyear fast field
+pub struct YearHistogram {
+	year_field: String,
+}
+
+impl YearHistogram {
+	pub fn new(year_field: &str) -> YearHistogram {
+		YearHistogram {
+			year_field: year_field.to_string(),
+		}
+	}
+}
+
+impl Collector for YearHistogram {
+	type Fruit = HashMap<i64, usize>;
+
+	type Child = YearHistogramSegmentCollector;
+
+	fn for_segment(&self, _: SegmentOrdinal, segment_reader: &SegmentReader) -> tantivy::Result<Self::Child> {
+		Ok(YearHistogramSegmentCollector {
+			years: segment_reader.fast_fields().i64(&self.year_field)?,
+			fruit: HashMap::new(),
+		})
+	}
+
+	fn requires_scoring(&self) -> bool {
+		false
+	}
+
+	fn merge_fruits(&self, child_fruits: Vec<HashMap<i64, usize>>) -> tantivy::Result<Self::Fruit> {
+		let mut merged: HashMap<i64, usize> = HashMap::new();
+
+		for fruit in child_fruits {
+			for (year, count) in fruit {
+				*merged.entry(year).or_insert(0) += count;
+			}
+		}
+
+		Ok(merged)
+	}
+}
+
+pub struct YearHistogramSegmentCollector {
+	years: Column<i64>,
+	fruit: HashMap<i64, usize>,
+}
+
+impl SegmentCollector for YearHistogramSegmentCollector {
+	type Fruit = HashMap<i64, usize>;
+
+	fn collect(&mut self, doc: DocId, _: Score) {
+		//books with no known date have no year
+		if let Some(year) = self.years.first(doc) {
+			*self.fruit.entry(year).or_insert(0) += 1;
 		}
 	}
 
@@ -608,11 +1759,12 @@ impl SegmentCollector for FieldCategoriesSegmentCollector {
 		//segmentReader.get_store_reader().get(docId) => slow (returns LZ4 block to decompress!)
 		//If it is a facet - segmentReader.facet_reader() then facet_reader.facet_ords() & facet_from_ords()
 		let document: TantivyDocument = self.store_reader.get(doc).unwrap();
-		let field_text = document.get_first(self.category_field).unwrap().as_str().unwrap();
-		//println!("pos: {} text:{:?}:", self.char_position, &field_text.chars());
-		//not populated - just ignore it
-		self.fruit
-			.insert(field_text.to_string(), self.fruit.get(field_text).unwrap_or(&0) + 1);
+		for field_text in document.get_all(self.category_field).filter_map(|val| val.as_str()) {
+			//println!("pos: {} text:{:?}:", self.char_position, &field_text.chars());
+			//not populated - just ignore it
+			self.fruit
+				.insert(field_text.to_string(), self.fruit.get(field_text).unwrap_or(&0) + 1);
+		}
 	}
 
 	fn harvest(self) -> Self::Fruit {